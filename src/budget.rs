@@ -0,0 +1,147 @@
+use super::*;
+
+/// A quota on a shared [`Heap`], see [`Heap::budget`].
+///
+/// Allocations fail once the quota is used up, even if the heap still has space.
+/// The quota is charged with the bytes taken from the heap, alignment padding included.
+/// Freed memory is credited back only when the heap can reclaim it, and as much as it reclaims.
+///
+/// Running out of quota is not reported to the OOM hook of the heap, and with
+/// the `stats` feature it doesn't count as a failed allocation either.
+pub struct Budget<'h, S: Storage, D: Direction = Downward> {
+    heap: &'h Heap<S, D>,
+    quota: Quota,
+}
+
+/// The bytes charged to a [`Budget`] and its limit.
+pub(crate) struct Quota {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl Quota {
+    /// Charge `cost` bytes, returns `false` if that's over the limit.
+    pub(crate) fn charge(&self, cost: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Relaxed, |used| {
+                used.checked_add(cost).filter(|&u| u <= self.limit)
+            })
+            .is_ok()
+    }
+
+    pub(crate) fn refund(&self, cost: usize) {
+        self.used.fetch_sub(cost, Ordering::AcqRel);
+    }
+}
+
+impl<'h, S: Storage, D: Direction> Budget<'h, S, D> {
+    pub(crate) const fn new(heap: &'h Heap<S, D>, limit: usize) -> Self {
+        Self {
+            heap,
+            quota: Quota {
+                limit,
+                used: AtomicUsize::new(0),
+            },
+        }
+    }
+
+    /// Returns the heap this budget allocates from.
+    pub fn heap(&self) -> &'h Heap<S, D> {
+        self.heap
+    }
+
+    /// Returns the quota in bytes.
+    pub fn limit(&self) -> usize {
+        self.quota.limit
+    }
+
+    /// Returns the amount of bytes charged to this budget.
+    pub fn used(&self) -> usize {
+        self.quota.used.load(Ordering::Relaxed)
+    }
+
+    /// Returns the amount of bytes left in this budget.
+    pub fn remained(&self) -> usize {
+        self.limit() - self.used()
+    }
+}
+
+unsafe impl<S: Storage, D: Direction> GlobalAlloc for Budget<'_, S, D> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.heap.bump_extended(layout, Some(&self.quota)) {
+            Ok(p) => p.as_ptr(),
+            Err(Exhausted::Heap(remained)) => self.heap.out_of_memory(layout, remained),
+            Err(Exhausted::Quota) => ptr::null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(released) = self.heap.free(ptr, layout) {
+            self.quota.refund(released);
+        }
+    }
+}
+
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let heap: Heap<Inline<256>> = Heap::new();
+        let net = heap.budget(64);
+        let ui = heap.budget(128);
+        let l = Layout::new::<[u8; 40]>();
+
+        let p0 = unsafe { net.alloc(l) };
+        assert!(!p0.is_null());
+        // Over quota, though the heap has space
+        assert!(unsafe { net.alloc(l) }.is_null());
+        assert_eq!(net.used(), 40);
+        assert_eq!(net.remained(), 24);
+
+        let p1 = unsafe { ui.alloc(l) };
+        assert!(!p1.is_null());
+        assert_eq!(ui.used(), 40);
+        assert_eq!(heap.remained(), 176);
+
+        // Leaked by the heap, so still charged
+        unsafe { net.dealloc(p0, l) };
+        assert_eq!(net.used(), 40);
+        // Freed in LIFO order, so credited back
+        unsafe { ui.dealloc(p1, l) };
+        assert_eq!(ui.used(), 0);
+        assert_eq!(heap.remained(), 216);
+    }
+
+    #[test]
+    fn test_budget_padding() {
+        let heap: Heap<InlineAligned<64, 8>> = Heap::new();
+        let budget = heap.budget(16);
+        let l0 = Layout::new::<u8>();
+        let l1 = Layout::new::<u64>();
+        let p0 = unsafe { budget.alloc(l0) };
+        let p1 = unsafe { budget.alloc(l1) };
+        assert!(!p1.is_null());
+        // Charged with the padding in front of the `u64` too
+        assert_eq!(budget.used(), 16);
+        assert_eq!(heap.used(), 16);
+        assert!(unsafe { budget.alloc(l0) }.is_null());
+
+        unsafe { budget.dealloc(p1, l1) };
+        assert_eq!(budget.used(), 1);
+        unsafe { budget.dealloc(p0, l0) };
+        assert_eq!(budget.used(), 0);
+        assert_eq!(heap.remained(), 64);
+    }
+
+    #[test]
+    fn test_budget_heap_full() {
+        let heap: Heap<Inline<32>> = Heap::new();
+        let budget = heap.budget(64);
+        assert!(unsafe { budget.alloc(Layout::new::<[u8; 40]>()) }.is_null());
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,97 @@
+use core::{alloc::Layout, ops::Range};
+
+/// The way a [`Heap`](crate::Heap) bumps, either [`Downward`] or [`Upward`].
+pub trait Direction: sealed::Sealed {}
+
+/// Allocate from the end of the storage towards its start, the default.
+pub struct Downward;
+
+/// Allocate from the start of the storage towards its end.
+///
+/// The most recent allocation then grows in place on `realloc`, so a `Vec` that
+/// keeps growing at the top of the heap is never copied.
+pub struct Upward;
+
+impl Direction for Downward {}
+impl Direction for Upward {}
+
+pub(crate) mod sealed {
+    use super::*;
+
+    /// All offsets are relative to the start of the storage.
+    pub trait Sealed {
+        /// Place `layout` in the free part of a storage at address `base`,
+        /// returns the new `remained` and the offset of the block.
+        fn place(
+            base: usize,
+            size: usize,
+            remained: usize,
+            layout: Layout,
+        ) -> Option<(usize, usize)>;
+
+        /// Returns `remained` right after and right before the block at `offset`
+        /// of `len` bytes was placed.
+        fn around(size: usize, offset: usize, len: usize) -> (usize, usize);
+
+        /// Returns the unused part of the storage.
+        fn free(size: usize, remained: usize) -> Range<usize>;
+
+        /// Returns the allocated part of the storage.
+        fn used(size: usize, remained: usize) -> Range<usize>;
+    }
+
+    impl Sealed for Downward {
+        fn place(
+            base: usize,
+            _size: usize,
+            remained: usize,
+            layout: Layout,
+        ) -> Option<(usize, usize)> {
+            let addr = (base + remained).checked_sub(layout.size())? & !(layout.align() - 1);
+            let offset = addr.checked_sub(base)?;
+            Some((offset, offset))
+        }
+
+        fn around(_size: usize, offset: usize, len: usize) -> (usize, usize) {
+            (offset, offset + len)
+        }
+
+        fn free(_size: usize, remained: usize) -> Range<usize> {
+            0..remained
+        }
+
+        fn used(size: usize, remained: usize) -> Range<usize> {
+            remained..size
+        }
+    }
+
+    impl Sealed for Upward {
+        fn place(
+            base: usize,
+            size: usize,
+            remained: usize,
+            layout: Layout,
+        ) -> Option<(usize, usize)> {
+            let start = base + size - remained;
+            let addr = start.checked_add(layout.align() - 1)? & !(layout.align() - 1);
+            let offset = addr - base;
+            let end = offset.checked_add(layout.size())?;
+            if end > size {
+                return None;
+            }
+            Some((size - end, offset))
+        }
+
+        fn around(size: usize, offset: usize, len: usize) -> (usize, usize) {
+            (size - offset - len, size - offset)
+        }
+
+        fn free(size: usize, remained: usize) -> Range<usize> {
+            size - remained..size
+        }
+
+        fn used(size: usize, remained: usize) -> Range<usize> {
+            0..size - remained
+        }
+    }
+}
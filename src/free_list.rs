@@ -0,0 +1,330 @@
+use crate::{AlreadyInitialized, BoxedSlice, ConstStorage, Pointer, Storage};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::RefCell,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ptr::{self, NonNull},
+};
+use critical_section::Mutex;
+
+/// A heap that can free memory, keeping free blocks in a list.
+///
+/// The [`Policy`] decides where freed blocks go in the list, see [`Heap2`](crate::Heap2)
+/// and [`Heap4`](crate::Heap4). It uses the same storage as [`Heap`](crate::Heap),
+/// so switching between them only takes changing the type.
+///
+/// The free list is guarded by a [critical section](https://docs.rs/critical-section),
+/// so allocating from interrupts is fine, but the target has to provide an implementation.
+pub struct FreeListHeap<S: Storage, P: Policy> {
+    storage: S,
+    list: Mutex<RefCell<FreeList>>,
+    _policy: PhantomData<P>,
+}
+
+/// Where freed blocks go in the list of a [`FreeListHeap`].
+pub trait Policy: sealed::Sealed {}
+
+pub(crate) mod sealed {
+    use super::*;
+
+    pub trait Sealed {
+        /// Put a free block into the list, `remained` is already accounted for.
+        fn insert(list: &mut FreeList, block: NonNull<Block>);
+    }
+}
+
+unsafe impl<S: Storage, P: Policy> Sync for FreeListHeap<S, P> {}
+
+impl<S: Storage, P: Policy> FreeListHeap<S, P> {
+    /// Create a new heap allocator over all of `storage`.
+    pub fn from_storage(storage: S) -> Self {
+        Self::with_storage(storage)
+    }
+
+    const fn with_storage(storage: S) -> Self {
+        Self {
+            storage,
+            list: Mutex::new(RefCell::new(FreeList::new())),
+            _policy: PhantomData,
+        }
+    }
+
+    /// Returns the amount of free bytes, including block headers.
+    pub fn remained(&self) -> usize {
+        self.with_list(|list| list.remained)
+    }
+
+    /// Returns the total amount of bytes.
+    pub fn capacity(&self) -> usize {
+        self.storage.size()
+    }
+
+    /// Run `f` on the free list inside a critical section, setting it up on first use.
+    pub(crate) fn with_list<R>(&self, f: impl FnOnce(&mut FreeList) -> R) -> R {
+        critical_section::with(|cs| {
+            let mut list = self.list.borrow_ref_mut(cs);
+            if !list.initialized {
+                let s = &self.storage;
+                let size = s.size();
+                // `Pointer` is empty until initialized.
+                if size != 0 {
+                    list.initialized = true;
+                    if let Some(block) = unsafe { FreeList::region(s.ptr(), size) } {
+                        list.remained = unsafe { block.as_ref().size };
+                        list.head = Some(block);
+                    }
+                }
+            }
+            f(&mut list)
+        })
+    }
+}
+
+#[allow(clippy::new_without_default)]
+impl<S: ConstStorage, P: Policy> FreeListHeap<S, P> {
+    /// Create a new heap allocator
+    pub const fn new() -> Self {
+        Self::with_storage(S::INIT)
+    }
+}
+
+impl<P: Policy> FreeListHeap<BoxedSlice, P> {
+    /// Create a new heap allocator from global heap.
+    pub fn new_boxed(size: usize) -> Self {
+        Self::from_storage(BoxedSlice::new(size))
+    }
+}
+
+impl<P: Policy> FreeListHeap<Pointer, P> {
+    /// Create an empty heap allocator
+    pub const fn empty() -> Self {
+        Self::with_storage(Pointer::empty())
+    }
+
+    /// Initialize the heap with `mem`.
+    pub fn init(&self, mem: &'static mut [MaybeUninit<u8>]) -> Result<(), AlreadyInitialized> {
+        let len = mem.len();
+        let ptr = NonNull::from(mem).cast::<u8>();
+        unsafe { self.init_with_nonnull(NonNull::slice_from_raw_parts(ptr, len)) }
+    }
+
+    /// Initialize the heap with the memory `mem` points to.
+    ///
+    /// # Safety
+    ///
+    /// `mem` must be valid for reads and writes for as long as the heap is used.
+    pub unsafe fn init_with_nonnull(&self, mem: NonNull<[u8]>) -> Result<(), AlreadyInitialized> {
+        if !self.storage.set(mem.cast(), mem.len()) {
+            return Err(AlreadyInitialized);
+        }
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// This function is safe if the following invariants hold:
+    ///
+    /// - `address` points to valid memory.
+    /// - `size` is correct.
+    /// - Call it only once.
+    pub unsafe fn init_with_ptr(&self, address: usize, size: usize) {
+        let ptr = unsafe { NonNull::new_unchecked(address as *mut u8) };
+        let r = unsafe { self.init_with_nonnull(NonNull::slice_from_raw_parts(ptr, size)) };
+        debug_assert!(r.is_ok(), "{}", AlreadyInitialized);
+    }
+}
+
+unsafe impl<S: Storage, P: Policy> GlobalAlloc for FreeListHeap<S, P> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.with_list(|list| match list.take(layout) {
+            Some((ptr, parts)) => {
+                for part in parts.into_iter().flatten() {
+                    P::insert(list, part);
+                }
+                ptr.as_ptr()
+            }
+            None => ptr::null_mut(),
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        let block = unsafe { block_of(ptr) };
+        self.with_list(|list| {
+            list.remained += unsafe { block.as_ref().size };
+            P::insert(list, block);
+        })
+    }
+}
+
+// ------------------------------------------------------------------
+
+/// The header in front of every block, free or allocated.
+// `pub` to appear in `sealed::Sealed`, the module itself is private.
+pub struct Block {
+    pub(crate) next: Option<NonNull<Block>>,
+    /// Size of the whole block, including this header.
+    pub(crate) size: usize,
+}
+
+pub(crate) const ALIGN: usize = align_of::<Block>();
+pub(crate) const HEADER: usize = size_of::<Block>();
+/// Leftovers smaller than this stay part of the allocated block.
+pub(crate) const MIN_BLOCK: usize = HEADER * 2;
+
+/// The unused parts in front of and behind an allocation.
+pub(crate) type Parts = [Option<NonNull<Block>>; 2];
+
+/// A singly linked list of free blocks.
+///
+/// The order of the list is up to the heap using it, blocks are taken from it
+/// first-fit in that order.
+pub struct FreeList {
+    pub(crate) head: Option<NonNull<Block>>,
+    /// Free bytes, including block headers.
+    pub(crate) remained: usize,
+    pub(crate) initialized: bool,
+}
+
+unsafe impl Send for FreeList {}
+
+impl FreeList {
+    pub(crate) const fn new() -> Self {
+        Self {
+            head: None,
+            remained: 0,
+            initialized: false,
+        }
+    }
+
+    /// Turn the memory region into a single block, returns `None` if it's too small.
+    ///
+    /// # Safety
+    ///
+    /// `base` must be valid for reads and writes of `size` bytes.
+    pub(crate) unsafe fn region(base: NonNull<u8>, size: usize) -> Option<NonNull<Block>> {
+        let start = base.as_ptr() as usize;
+        let front = start.next_multiple_of(ALIGN) - start;
+        let size = size.checked_sub(front)? & !(ALIGN - 1);
+        if size < MIN_BLOCK {
+            return None;
+        }
+        let block = unsafe { base.byte_add(front) }.cast::<Block>();
+        unsafe { block.write(Block { next: None, size }) };
+        Some(block)
+    }
+
+    /// Take the first block that fits `layout` out of the list.
+    ///
+    /// Returns the payload and the unused parts in front of and behind it,
+    /// which must be given back to the list.
+    pub(crate) fn take(&mut self, layout: Layout) -> Option<(NonNull<u8>, Parts)> {
+        let mut prev: Option<NonNull<Block>> = None;
+        let mut cur = self.head;
+        while let Some(mut block) = cur {
+            let b = unsafe { block.as_mut() };
+            if let Some(front) = place(block, b.size, layout) {
+                match prev {
+                    None => self.head = b.next,
+                    Some(mut p) => unsafe { p.as_mut().next = b.next },
+                }
+                self.remained -= b.size;
+                let (payload, parts) = unsafe { split(block, front, layout) };
+                for part in parts.iter().flatten() {
+                    self.remained += unsafe { part.as_ref().size };
+                }
+                return Some((payload, parts));
+            }
+            prev = cur;
+            cur = b.next;
+        }
+        None
+    }
+}
+
+/// Returns the block header of an allocated payload.
+///
+/// # Safety
+///
+/// `ptr` must be a payload returned by [`FreeList::take`].
+pub(crate) unsafe fn block_of(ptr: *mut u8) -> NonNull<Block> {
+    unsafe { NonNull::new_unchecked(ptr.byte_sub(HEADER)).cast() }
+}
+
+fn allocated_size(layout: Layout) -> Option<usize> {
+    HEADER
+        .checked_add(layout.size())?
+        .checked_next_multiple_of(ALIGN)
+}
+
+/// Returns the offset of the allocated block inside a free block, if `layout` fits.
+fn place(block: NonNull<Block>, block_size: usize, layout: Layout) -> Option<usize> {
+    let align = layout.align().max(ALIGN);
+    let start = block.as_ptr() as usize;
+    let mut front = (start + HEADER).next_multiple_of(align) - HEADER - start;
+    if front != 0 && front < MIN_BLOCK {
+        // The front part has to be able to stand as a free block on its own.
+        front = (start + HEADER + MIN_BLOCK).next_multiple_of(align) - HEADER - start;
+    }
+    (front.checked_add(allocated_size(layout)?)? <= block_size).then_some(front)
+}
+
+/// Split `block` at `front` and behind the allocation.
+unsafe fn split(mut block: NonNull<Block>, front: usize, layout: Layout) -> (NonNull<u8>, Parts) {
+    let block_size = unsafe { block.as_ref().size };
+    let mut size = allocated_size(layout).unwrap();
+
+    let front_part = (front != 0).then(|| {
+        unsafe { block.as_mut().size = front };
+        block
+    });
+
+    let used = unsafe { block.byte_add(front) };
+    let rest = block_size - front - size;
+    let back_part = if rest >= MIN_BLOCK {
+        let back = unsafe { used.byte_add(size) };
+        unsafe {
+            back.write(Block {
+                next: None,
+                size: rest,
+            })
+        };
+        Some(back)
+    } else {
+        size += rest;
+        None
+    };
+
+    unsafe { used.write(Block { next: None, size }) };
+    (
+        unsafe { used.byte_add(HEADER) }.cast(),
+        [front_part, back_part],
+    )
+}
+
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Heap2, Heap4};
+    #[cfg(not(feature = "std"))]
+    use alloc::boxed::Box;
+
+    fn check_pointer<P: Policy>(heap: &'static FreeListHeap<Pointer, P>) {
+        assert!(unsafe { heap.alloc(Layout::new::<u32>()) }.is_null());
+        heap.init(Box::leak(Box::new([MaybeUninit::uninit(); 64])))
+            .unwrap();
+        let p = unsafe { heap.alloc(Layout::new::<u32>()) };
+        assert!(!p.is_null());
+        assert_eq!(heap.capacity(), 64);
+    }
+
+    #[test]
+    fn test_free_list_pointer() {
+        static HEAP2: Heap2<Pointer> = Heap2::empty();
+        static HEAP4: Heap4<Pointer> = Heap4::empty();
+        check_pointer(&HEAP2);
+        check_pointer(&HEAP4);
+    }
+}
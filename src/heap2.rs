@@ -0,0 +1,98 @@
+use crate::free_list::{Block, FreeList, FreeListHeap, Policy, sealed::Sealed};
+use core::ptr::NonNull;
+
+/// A best-fit heap that can free memory, without merging adjacent free blocks.
+///
+/// It's similar to [heap2 in FreeRTOS](https://github.com/FreeRTOS/FreeRTOS-Kernel/blob/main/portable/MemMang/heap_2.c).
+/// Freed blocks are reused, but they are never merged, so the heap fragments
+/// when allocations of many different sizes are freed.
+pub type Heap2<S> = FreeListHeap<S, BestFit>;
+
+/// Keep the free list sorted by size, so the first fit is the best fit.
+pub struct BestFit;
+
+impl Policy for BestFit {}
+
+impl Sealed for BestFit {
+    fn insert(list: &mut FreeList, mut block: NonNull<Block>) {
+        let size = unsafe { block.as_ref().size };
+        let mut prev: Option<NonNull<Block>> = None;
+        let mut cur = list.head;
+        while let Some(b) = cur {
+            if unsafe { b.as_ref().size } >= size {
+                break;
+            }
+            prev = cur;
+            cur = unsafe { b.as_ref().next };
+        }
+        unsafe { block.as_mut().next = cur };
+        match prev {
+            None => list.head = Some(block),
+            Some(mut p) => unsafe { p.as_mut().next = Some(block) },
+        }
+    }
+}
+
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InlineAligned, free_list::HEADER};
+    use core::alloc::{GlobalAlloc, Layout};
+
+    #[test]
+    fn test_heap2() {
+        let heap: Heap2<InlineAligned<256, 16>> = Heap2::new();
+        assert_eq!(heap.remained(), 256);
+        let l = Layout::new::<[u64; 4]>();
+        let p0 = unsafe { heap.alloc(l) };
+        assert_eq!(heap.remained(), 256 - 32 - HEADER);
+        unsafe { heap.dealloc(p0, l) };
+        assert_eq!(heap.remained(), 256);
+        // Reused without merging
+        let p1 = unsafe { heap.alloc(l) };
+        assert_eq!(p0, p1);
+        unsafe { heap.dealloc(p1, l) };
+    }
+
+    #[test]
+    fn test_heap2_best_fit() {
+        let heap: Heap2<InlineAligned<512, 16>> = Heap2::new();
+        let small = Layout::new::<[u8; 16]>();
+        let large = Layout::new::<[u8; 64]>();
+        let a = unsafe { heap.alloc(large) };
+        let _guard0 = unsafe { heap.alloc(small) };
+        let b = unsafe { heap.alloc(small) };
+        let _guard1 = unsafe { heap.alloc(small) };
+        unsafe { heap.dealloc(a, large) };
+        unsafe { heap.dealloc(b, small) };
+
+        // The smaller free block is picked even though the larger one comes first in memory.
+        assert_eq!(unsafe { heap.alloc(small) }, b);
+        assert_eq!(unsafe { heap.alloc(large) }, a);
+    }
+
+    #[test]
+    fn test_heap2_no_coalescing() {
+        let heap: Heap2<InlineAligned<128, 16>> = Heap2::new();
+        let l = Layout::new::<[u8; 32]>();
+        let a = unsafe { heap.alloc(l) };
+        let b = unsafe { heap.alloc(l) };
+        assert!(unsafe { heap.alloc(l) }.is_null());
+        unsafe { heap.dealloc(a, l) };
+        unsafe { heap.dealloc(b, l) };
+        assert!(unsafe { heap.alloc(Layout::new::<[u8; 64]>()) }.is_null());
+    }
+
+    #[test]
+    fn test_heap2_align() {
+        let heap = Heap2::new_boxed(1024);
+        for align in [1, 8, 64, 256] {
+            let l = Layout::from_size_align(24, align).unwrap();
+            let p = unsafe { heap.alloc(l) };
+            assert!(!p.is_null());
+            assert_eq!(p as usize % align, 0);
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use crate::free_list::{Block, FreeList, FreeListHeap, Policy, sealed::Sealed};
+use core::ptr::NonNull;
+
+/// A first-fit heap that merges adjacent free blocks.
+///
+/// It's similar to [heap4 in FreeRTOS](https://github.com/FreeRTOS/FreeRTOS-Kernel/blob/main/portable/MemMang/heap_4.c).
+/// The free list is kept in address order, so every allocation and every free
+/// walks it at most once, which bounds their time by the number of free blocks.
+pub type Heap4<S> = FreeListHeap<S, FirstFit>;
+
+/// Keep the free list sorted by address, merging freed blocks with adjacent free blocks.
+pub struct FirstFit;
+
+impl Policy for FirstFit {}
+
+impl Sealed for FirstFit {
+    fn insert(list: &mut FreeList, mut block: NonNull<Block>) {
+        let mut prev: Option<NonNull<Block>> = None;
+        let mut next = list.head;
+        while let Some(b) = next {
+            if b > block {
+                break;
+            }
+            prev = next;
+            next = unsafe { b.as_ref().next };
+        }
+
+        let b = unsafe { block.as_mut() };
+        b.next = next;
+        if let Some(n) = next
+            && unsafe { block.byte_add(b.size) } == n
+        {
+            let n = unsafe { n.as_ref() };
+            b.size += n.size;
+            b.next = n.next;
+        }
+
+        match prev {
+            None => list.head = Some(block),
+            Some(mut p) => {
+                let p = unsafe { p.as_mut() };
+                if unsafe { NonNull::from(&mut *p).byte_add(p.size) } == block {
+                    p.size += b.size;
+                    p.next = b.next;
+                } else {
+                    p.next = Some(block);
+                }
+            }
+        }
+    }
+}
+
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InlineAligned, free_list::HEADER};
+    use core::{
+        alloc::{GlobalAlloc, Layout},
+        ptr,
+    };
+
+    #[test]
+    fn test_heap4() {
+        let heap: Heap4<InlineAligned<256, 16>> = Heap4::new();
+        assert_eq!(heap.remained(), 256);
+        let l = Layout::new::<[u64; 4]>();
+        let p0 = unsafe { heap.alloc(l) };
+        let p1 = unsafe { heap.alloc(l) };
+        assert_eq!(heap.remained(), 256 - 2 * (32 + HEADER));
+        unsafe { heap.dealloc(p0, l) };
+        unsafe { heap.dealloc(p1, l) };
+        assert_eq!(heap.remained(), 256);
+        assert_eq!(
+            heap.with_list(|l| l.head.map(|b| unsafe { b.as_ref().size })),
+            Some(256)
+        );
+    }
+
+    #[test]
+    fn test_heap4_coalescing() {
+        let heap: Heap4<InlineAligned<96, 16>> = Heap4::new();
+        let l = Layout::new::<[u8; 16]>();
+        let a = unsafe { heap.alloc(l) };
+        let b = unsafe { heap.alloc(l) };
+        let c = unsafe { heap.alloc(l) };
+        assert!(!c.is_null());
+        assert!(unsafe { heap.alloc(l) }.is_null());
+
+        // Merged with the next block, then with the previous one
+        unsafe { heap.dealloc(c, l) };
+        unsafe { heap.dealloc(a, l) };
+        unsafe { heap.dealloc(b, l) };
+        let big = Layout::new::<[u8; 80]>();
+        assert_eq!(unsafe { heap.alloc(big) }, a);
+    }
+
+    #[test]
+    fn test_heap4_align() {
+        let heap = Heap4::new_boxed(1024);
+        let mut ps = [(ptr::null_mut(), Layout::new::<u8>()); 4];
+        for (i, align) in [1, 8, 64, 256].into_iter().enumerate() {
+            let l = Layout::from_size_align(24, align).unwrap();
+            let p = unsafe { heap.alloc(l) };
+            assert!(!p.is_null());
+            assert_eq!(p as usize % align, 0);
+            ps[i] = (p, l);
+        }
+        for (p, l) in ps {
+            unsafe { heap.dealloc(p, l) };
+        }
+        assert_eq!(
+            heap.remained(),
+            heap.with_list(|l| l.head.map_or(0, |b| unsafe { b.as_ref().size }))
+        );
+    }
+}
@@ -1,219 +1,1448 @@
 #![doc = include_str!("../README.md")]
 #![cfg_attr(not(feature = "std"), no_std)]
+#![cfg_attr(feature = "allocator-api", feature(allocator_api))]
 
 #[cfg(not(feature = "std"))]
 extern crate alloc;
 
 #[cfg(not(feature = "std"))]
-use alloc::{boxed::Box, vec::Vec};
+use alloc::alloc::{self as global, handle_alloc_error};
 use core::{
     alloc::{GlobalAlloc, Layout},
     cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
     mem::MaybeUninit,
+    ops::{Deref, Range},
     ptr::{self, NonNull},
+    slice, str,
 };
-use portable_atomic::{AtomicUsize, Ordering};
+use portable_atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+#[cfg(feature = "std")]
+use std::alloc::{self as global, handle_alloc_error};
+
+mod budget;
+mod direction;
+mod free_list;
+mod heap2;
+mod heap4;
+mod multi_region;
+mod padding;
+pub use budget::Budget;
+use budget::Quota;
+pub use direction::{Direction, Downward, Upward};
+pub use free_list::{FreeListHeap, Policy};
+pub use heap2::{BestFit, Heap2};
+pub use heap4::{FirstFit, Heap4};
+pub use multi_region::MultiRegion;
+
+#[cfg(feature = "stats")]
+mod stats;
+#[cfg(feature = "stats")]
+pub use stats::HeapStats;
+#[cfg(feature = "stats")]
+use stats::Stats;
+
+use padding::Padding;
 
 /// The simplest possible heap.
-pub struct Heap<S: Storage> {
-    storage: UnsafeCell<S>,
+///
+/// It bumps [`Downward`] by default, see [`Upward`] for the other way.
+pub struct Heap<S: Storage, D: Direction = Downward> {
+    storage: S,
     remained: AtomicUsize,
+    padding: Padding,
+    oom: Oom,
+    /// Set during [`Heap::scope`], extensions are left alone then.
+    scoped: AtomicBool,
+    #[cfg(feature = "stats")]
+    stats: Stats,
+    _direction: PhantomData<D>,
 }
 
-unsafe impl<S: Storage> Sync for Heap<S> {}
+unsafe impl<S: Storage, D: Direction> Sync for Heap<S, D> {}
+
+impl<S: Storage, D: Direction> Heap<S, D> {
+    /// Create a new heap allocator over all of `storage`.
+    pub fn from_storage(storage: S) -> Self {
+        let size = storage.size();
+        unsafe { Self::new_with_storage(storage, size) }
+    }
 
-impl<S: Storage> Heap<S> {
     /// Create a new heap allocator
-    pub const fn new_with_storage(storage: S, size: usize) -> Self {
+    ///
+    /// # Safety
+    ///
+    /// `size` must not exceed [`Storage::size`] of `storage`.
+    pub const unsafe fn new_with_storage(storage: S, size: usize) -> Self {
         Self {
-            storage: UnsafeCell::new(storage),
+            storage,
             remained: AtomicUsize::new(size),
+            padding: Padding::new(),
+            oom: Oom::new(),
+            scoped: AtomicBool::new(false),
+            #[cfg(feature = "stats")]
+            stats: Stats::new(size),
+            _direction: PhantomData,
         }
     }
 
-    /// Returns the amount of unused bytes.
+    /// Returns the amount of unused bytes, including extensions.
     pub fn remained(&self) -> usize {
-        self.remained.load(Ordering::Relaxed)
+        self.remained.load(Ordering::Relaxed) + self.extension().map_or(0, Heap::remained)
+    }
+
+    /// Returns the total amount of bytes, including extensions.
+    pub fn capacity(&self) -> usize {
+        self.storage_size() + self.extension().map_or(0, Heap::capacity)
+    }
+
+    fn storage_size(&self) -> usize {
+        self.storage.size()
+    }
+
+    fn extension(&self) -> Option<&Heap<Pointer>> {
+        self.storage.extension()
+    }
+
+    /// Returns the amount of allocated bytes, including padding.
+    pub fn used(&self) -> usize {
+        self.capacity() - self.remained()
+    }
+
+    /// Returns the lowest amount of unused bytes ever seen in the storage, excluding extensions.
+    #[cfg(feature = "stats")]
+    pub fn min_ever_remained(&self) -> usize {
+        self.stats.min_remained.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of successful allocations.
+    #[cfg(feature = "stats")]
+    pub fn allocation_count(&self) -> usize {
+        self.stats.alloc_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of failed allocations.
+    #[cfg(feature = "stats")]
+    pub fn failed_allocation_count(&self) -> usize {
+        self.stats.failed_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the amount of bytes left behind when `realloc` couldn't resize in place
+    /// and the old block couldn't be given back.
+    #[cfg(feature = "stats")]
+    pub fn realloc_lost_bytes(&self) -> usize {
+        self.stats.realloc_lost.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of deallocations that leaked, see [`Heap::leaked_bytes`].
+    #[cfg(feature = "stats")]
+    pub fn leak_count(&self) -> usize {
+        self.stats.leak_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the amount of bytes deallocated but not given back, including extensions.
+    ///
+    /// Only the most recent allocation can be given back, every other drop ends up here.
+    #[cfg(feature = "stats")]
+    pub fn leaked_bytes(&self) -> usize {
+        self.stats.leaked.load(Ordering::Relaxed)
+    }
+
+    /// Returns a snapshot of the usage statistics of the storage, excluding extensions.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> HeapStats {
+        let capacity = self.storage_size();
+        let remaining = self.remained.load(Ordering::Relaxed);
+        HeapStats {
+            capacity,
+            used: capacity - remaining,
+            remaining,
+            peak: capacity - self.min_ever_remained(),
+            alloc_count: self.allocation_count(),
+            failed_count: self.failed_allocation_count(),
+            padding: self.stats.padding.load(Ordering::Relaxed),
+            realloc_lost: self.realloc_lost_bytes(),
+            leak_count: self.leak_count(),
+            leaked: self.leaked_bytes(),
+        }
+    }
+
+    /// Set a function to be called whenever an allocation fails.
+    pub fn set_oom_hook(&self, hook: fn(&HeapOomInfo)) {
+        self.oom.set_hook(hook);
+    }
+
+    /// Returns the most recent allocation failure.
+    pub fn last_oom(&self) -> Option<HeapOomInfo> {
+        self.oom.last(self.capacity())
+    }
+
+    /// Allocate `layout` without reporting failures.
+    ///
+    /// The bytes the block takes, padding included, are charged to `quota` before they are taken.
+    fn bump(&self, layout: Layout, quota: Option<&Quota>) -> Result<NonNull<u8>, Exhausted> {
+        let mut old_remained = self.remained.load(Ordering::Acquire);
+        // The storage itself may have any alignment, so align the absolute address.
+        let base = self.storage.ptr().as_ptr();
+        let size = self.storage_size();
+        loop {
+            let Some((remained, offset)) = D::place(base as usize, size, old_remained, layout)
+            else {
+                return Err(Exhausted::Heap(old_remained));
+            };
+            let cost = old_remained - remained;
+            if let Some(q) = quota
+                && !q.charge(cost)
+            {
+                return Err(Exhausted::Quota);
+            }
+
+            match self.remained.compare_exchange_weak(
+                old_remained,
+                remained,
+                Ordering::SeqCst,
+                Ordering::Relaxed,
+            ) {
+                Err(x) => {
+                    if let Some(q) = quota {
+                        q.refund(cost);
+                    }
+                    old_remained = x;
+                }
+                Ok(_) => {
+                    let pad = old_remained - layout.size() - remained;
+                    self.padding.push(remained, pad);
+                    #[cfg(feature = "stats")]
+                    self.stats.on_alloc(remained, pad);
+                    return Ok(unsafe { NonNull::new_unchecked(base.add(offset)) });
+                }
+            }
+        }
+    }
+
+    /// Give the block back if it's the most recent allocation.
+    ///
+    /// Returns the bytes given back, padding included, or `None` if it wasn't.
+    fn release(&self, ptr: *mut u8, layout: Layout) -> Option<usize> {
+        if !(self.base()..self.end()).contains(&ptr.cast_const())
+            && let Some(e) = self.extension()
+        {
+            return e.release(ptr, layout);
+        }
+        let offset = ptr as usize - self.base() as usize;
+        let (after, before) = D::around(self.storage_size(), offset, layout.size());
+        let prev = match self.find_padding(after, layout.size()) {
+            Some(found) if self.padding.take(&found) => found.prev,
+            _ => before,
+        };
+        // Fails when someone else has allocated since, in which case the block is leaked.
+        self.remained
+            .compare_exchange(after, prev, Ordering::SeqCst, Ordering::Relaxed)
+            .ok()?;
+        #[cfg(feature = "stats")]
+        self.stats.on_release_padding(prev - before);
+        Some(prev - after)
+    }
+
+    /// Look up the padding of the block at `after` of `len` bytes in the storage.
+    fn find_padding(&self, after: usize, len: usize) -> Option<padding::Found> {
+        // An empty block shares its key with the one above.
+        if len == 0 {
+            return None;
+        }
+        self.padding.find(after, len)
+    }
+
+    /// Like [`Heap::release`], but records the block as leaked if it wasn't given back.
+    fn free(&self, ptr: *mut u8, layout: Layout) -> Option<usize> {
+        let released = self.release(ptr, layout);
+        #[cfg(feature = "stats")]
+        if released.is_none() {
+            self.stats.on_leak(layout.size());
+        }
+        released
+    }
+
+    /// Like [`Heap::release`], but records the block as lost to a move if it wasn't given back.
+    fn release_moved(&self, ptr: *mut u8, layout: Layout) {
+        if self.release(ptr, layout).is_none() {
+            #[cfg(feature = "stats")]
+            self.stats.on_realloc_lost(layout.size());
+        }
+    }
+
+    /// Resize the most recent allocation as if it was released and allocated again,
+    /// moving the contents if the block moves.
+    ///
+    /// Returns `None` if `ptr` isn't the most recent allocation, there's no room,
+    /// or the block would move but `may_move` is `false`.
+    fn resize_top(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+        may_move: bool,
+    ) -> Option<NonNull<u8>> {
+        let base = self.storage.ptr().as_ptr();
+        let size = self.storage_size();
+        let offset = (ptr.as_ptr() as usize).checked_sub(base as usize)?;
+        if offset.checked_add(old_layout.size())? > size || new_layout.size() == 0 {
+            return None;
+        }
+        let (after, before) = D::around(size, offset, old_layout.size());
+        let found = self.find_padding(after, old_layout.size());
+        let prev = found.as_ref().map_or(before, |f| f.prev);
+        let (remained, new_offset) = D::place(base as usize, size, prev, new_layout)?;
+        if !may_move && new_offset != offset {
+            return None;
+        }
+        self.remained
+            .compare_exchange(after, remained, Ordering::SeqCst, Ordering::Relaxed)
+            .ok()?;
+        // Kept until now, as the block stays live if it isn't the most recent one.
+        // If it's been evicted meanwhile, the padding is still there all the same.
+        if let Some(found) = &found {
+            self.padding.take(found);
+        }
+        let pad = prev - new_layout.size() - remained;
+        #[cfg(feature = "stats")]
+        self.stats.on_resize(remained, prev - before, pad);
+
+        self.padding.push(remained, pad);
+
+        let p = unsafe { base.add(new_offset) };
+        if new_offset != offset {
+            // The blocks may overlap.
+            unsafe { ptr::copy(ptr.as_ptr(), p, old_layout.size().min(new_layout.size())) };
+        }
+        Some(unsafe { NonNull::new_unchecked(p) })
+    }
+
+    /// Like [`Heap::bump`], then tries the extensions in order, unless inside a scope.
+    fn bump_extended(
+        &self,
+        layout: Layout,
+        quota: Option<&Quota>,
+    ) -> Result<NonNull<u8>, Exhausted> {
+        self.bump(layout, quota)
+            .or_else(|e| match (e, self.extension()) {
+                // Extensions aren't rewound when the scope returns.
+                (Exhausted::Heap(remained), Some(ext)) if !self.scoped.load(Ordering::Relaxed) => {
+                    ext.bump_extended(layout, quota).map_err(|e| match e {
+                        Exhausted::Heap(r) => Exhausted::Heap(remained + r),
+                        Exhausted::Quota => Exhausted::Quota,
+                    })
+                }
+                _ => Err(e),
+            })
+    }
+
+    #[cold]
+    fn out_of_memory(&self, layout: Layout, remained: usize) -> *mut u8 {
+        #[cfg(feature = "stats")]
+        self.stats.on_failure();
+        self.oom.report(HeapOomInfo {
+            layout,
+            remained,
+            capacity: self.capacity(),
+        });
+        ptr::null_mut()
+    }
+
+    /// Returns the start of the storage.
+    pub fn base(&self) -> *const u8 {
+        self.storage.ptr().as_ptr()
+    }
+
+    /// Returns the end of the storage, one past the last byte.
+    pub fn end(&self) -> *const u8 {
+        self.base().wrapping_add(self.storage_size())
+    }
+
+    /// Returns the allocated part of the storage, including padding.
+    pub fn used_range(&self) -> Range<*const u8> {
+        let used = D::used(self.storage_size(), self.remained.load(Ordering::Relaxed));
+        self.base().wrapping_add(used.start)..self.base().wrapping_add(used.end)
+    }
+
+    /// Returns `true` if `ptr` points into the storage or an extension of this heap.
+    pub fn owns(&self, ptr: *const u8) -> bool {
+        (self.base()..self.end()).contains(&ptr) || self.extension().is_some_and(|e| e.owns(ptr))
+    }
+
+    /// Returns `true` if `ptr` points into the allocated part of the storage.
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        self.used_range().contains(&ptr)
+    }
+
+    /// Claim all unused bytes of the storage at once, e.g. to hand them to a general purpose allocator.
+    ///
+    /// Returns `None` if there's nothing left. Extensions are not touched.
+    ///
+    /// The memory is borrowed from the heap, so it can't outlive a scope that gives it back:
+    ///
+    /// ```rust compile_fail
+    /// use heap1::{Heap, Inline};
+    ///
+    /// let mut heap: Heap<Inline<64>> = Heap::new();
+    /// let mem = heap.scope(|s| s.take_remaining());
+    /// ```
+    #[allow(clippy::mut_from_ref)]
+    pub fn take_remaining(&self) -> Option<&mut [MaybeUninit<u8>]> {
+        let remained = self.remained.swap(0, Ordering::AcqRel);
+        if remained == 0 {
+            return None;
+        }
+        self.padding.clear();
+        #[cfg(feature = "stats")]
+        self.stats.min_remained.fetch_min(0, Ordering::Relaxed);
+        let free = D::free(self.storage_size(), remained);
+        let start = unsafe { self.storage.ptr().add(free.start) };
+        Some(unsafe { slice::from_raw_parts_mut(start.cast().as_ptr(), remained) })
+    }
+
+    /// Carve `size` bytes out of this heap as an independent child heap.
+    ///
+    /// Returns `None` if this heap runs out of memory.
+    pub fn split_off(&self, size: usize) -> Option<Heap<SubHeap<'_>>> {
+        let layout = Layout::from_size_align(size, align_of::<usize>()).ok()?;
+        let ptr = self.try_alloc_layout(layout)?;
+        Some(Heap::from_storage(SubHeap {
+            ptr,
+            size,
+            _parent: PhantomData,
+        }))
+    }
+
+    /// Create a handle that allocates from this heap, but at most `limit` bytes at once.
+    pub fn budget(&self, limit: usize) -> Budget<'_, S, D> {
+        Budget::new(self, limit)
+    }
+
+    /// Returns a marker of the current allocation position, see [`Heap::rewind`].
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            remained: self.remained.load(Ordering::Acquire),
+            #[cfg(feature = "stats")]
+            padding: self.stats.padding.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Release everything allocated after `checkpoint` was taken.
+    ///
+    /// Only the storage of this heap is rewound, memory allocated from extensions
+    /// since is not given back.
+    ///
+    /// # Safety
+    ///
+    /// This function is safe if the following invariants hold:
+    ///
+    /// - `checkpoint` was taken from this heap.
+    /// - None of the memory allocated after `checkpoint` is used anymore.
+    pub unsafe fn rewind(&self, checkpoint: Checkpoint) {
+        self.padding.clear();
+        #[cfg(feature = "stats")]
+        self.stats
+            .padding
+            .store(checkpoint.padding, Ordering::Relaxed);
+        self.remained.store(checkpoint.remained, Ordering::Release);
+    }
+
+    /// Run `f` and release everything it allocated once it returns.
+    ///
+    /// The heap is exclusively borrowed during the scope, so nothing else can
+    /// allocate from it and nothing allocated inside can escape. Allocations inside
+    /// only come from the storage of this heap, its extensions are not used.
+    pub fn scope<R>(&mut self, f: impl FnOnce(&Scope<'_, S, D>) -> R) -> R {
+        *self.scoped.get_mut() = true;
+        let scope = Scope {
+            checkpoint: self.checkpoint(),
+            heap: self,
+        };
+        f(&scope)
+    }
+}
+
+/// Typed allocation, tied to the lifetime of the heap.
+///
+/// Values moved into the heap are never dropped.
+///
+/// Note that [`Heap::alloc`] shadows [`GlobalAlloc::alloc`] in method call syntax,
+/// use `GlobalAlloc::alloc(&heap, layout)` for raw allocation.
+///
+/// # Panics
+///
+/// All of these functions panic through [`handle_alloc_error`] if the heap runs out of memory.
+#[allow(clippy::mut_from_ref)]
+impl<S: Storage, D: Direction> Heap<S, D> {
+    /// Allocate space for `value` and move it into the heap.
+    pub fn alloc<T>(&self, value: T) -> &mut T {
+        self.alloc_with(|| value)
+    }
+
+    /// Allocate space for a `T` and initialize it with the result of `f`.
+    pub fn alloc_with<T>(&self, f: impl FnOnce() -> T) -> &mut T {
+        let p = self.alloc_layout(Layout::new::<T>()).cast::<T>();
+        unsafe {
+            p.write(f());
+            &mut *p.as_ptr()
+        }
+    }
+
+    /// Allocate a copy of `src`.
+    pub fn alloc_slice_copy<T: Copy>(&self, src: &[T]) -> &mut [T] {
+        let p = self.alloc_layout(Layout::for_value(src)).cast::<T>();
+        unsafe {
+            ptr::copy_nonoverlapping(src.as_ptr(), p.as_ptr(), src.len());
+            slice::from_raw_parts_mut(p.as_ptr(), src.len())
+        }
+    }
+
+    /// Allocate a slice of `len` elements, initializing the element at index `i` with `f(i)`.
+    pub fn alloc_slice_fill_with<T>(&self, len: usize, mut f: impl FnMut(usize) -> T) -> &mut [T] {
+        let layout = Layout::array::<T>(len).expect("capacity overflow");
+        let p = self.alloc_layout(layout).cast::<T>();
+        unsafe {
+            for i in 0..len {
+                p.add(i).write(f(i));
+            }
+            slice::from_raw_parts_mut(p.as_ptr(), len)
+        }
+    }
+
+    /// Allocate a copy of `src`.
+    pub fn alloc_str(&self, src: &str) -> &mut str {
+        let bytes = self.alloc_slice_copy(src.as_bytes());
+        unsafe { str::from_utf8_unchecked_mut(bytes) }
+    }
+
+    fn alloc_layout(&self, layout: Layout) -> NonNull<u8> {
+        self.try_alloc_layout(layout)
+            .unwrap_or_else(|| handle_alloc_error(layout))
+    }
+
+    fn try_alloc_layout(&self, layout: Layout) -> Option<NonNull<u8>> {
+        if layout.size() == 0 {
+            return Some(dangling(layout));
+        }
+        NonNull::new(unsafe { GlobalAlloc::alloc(self, layout) })
+    }
+}
+
+/// Zero-sized values don't need any memory, only a well aligned pointer.
+fn dangling(layout: Layout) -> NonNull<u8> {
+    unsafe { NonNull::new_unchecked(ptr::without_provenance_mut(layout.align())) }
+}
+
+/// One-time allocation from a `static` heap, in the manner of `static_cell`.
+///
+/// These functions return `None` instead of panicking if the heap runs out of memory.
+#[allow(clippy::mut_from_ref)]
+impl<S: Storage, D: Direction> Heap<S, D> {
+    /// Move `value` into the heap.
+    pub fn alloc_static<T>(&'static self, value: T) -> Option<&'static mut T> {
+        self.alloc_static_uninit().map(|p| p.write(value))
+    }
+
+    /// Allocate uninitialized space for a `T`.
+    pub fn alloc_static_uninit<T>(&'static self) -> Option<&'static mut MaybeUninit<T>> {
+        let p = self.try_alloc_layout(Layout::new::<T>())?.cast();
+        Some(unsafe { &mut *p.as_ptr() })
+    }
+
+    /// Allocate a slice of `len` copies of `value`.
+    pub fn alloc_static_slice<T: Copy>(
+        &'static self,
+        len: usize,
+        value: T,
+    ) -> Option<&'static mut [T]> {
+        let s = self.alloc_static_uninit_slice(len)?;
+        s.fill(MaybeUninit::new(value));
+        Some(unsafe { &mut *(s as *mut [MaybeUninit<T>] as *mut [T]) })
+    }
+
+    /// Allocate an uninitialized slice of `len` elements.
+    pub fn alloc_static_uninit_slice<T>(
+        &'static self,
+        len: usize,
+    ) -> Option<&'static mut [MaybeUninit<T>]> {
+        let p = self.try_alloc_layout(Layout::array::<T>(len).ok()?)?.cast();
+        Some(unsafe { slice::from_raw_parts_mut(p.as_ptr(), len) })
     }
 }
 
 #[allow(clippy::new_without_default)]
-impl<S: ConstStorage> Heap<S> {
+impl<S: ConstStorage, D: Direction> Heap<S, D> {
     /// Create a new heap allocator
     pub const fn new() -> Self {
-        Self::new_with_storage(S::INIT, S::SIZE)
+        unsafe { Self::new_with_storage(S::INIT, S::SIZE) }
     }
 }
 
 impl Heap<BoxedSlice> {
     /// Create a new heap allocator from global heap.
+    ///
+    /// For an [`Upward`] heap, pass [`BoxedSlice::new`] to [`Heap::from_storage`].
     pub fn new_boxed(size: usize) -> Self {
-        Self::new_with_storage(BoxedSlice::new(size), size)
+        Self::from_storage(BoxedSlice::new(size))
+    }
+}
+
+impl Heap<Pointer> {
+    /// Create an empty heap allocator
+    ///
+    /// For an [`Upward`] heap, use [`Heap::new`] with the type spelled out.
+    pub const fn empty() -> Self {
+        unsafe { Self::new_with_storage(Pointer::empty(), 0) }
+    }
+}
+
+impl<D: Direction> Heap<Pointer, D> {
+    /// Initialize the heap with `mem`.
+    pub fn init(&self, mem: &'static mut [MaybeUninit<u8>]) -> Result<(), AlreadyInitialized> {
+        let len = mem.len();
+        let ptr = NonNull::from(mem).cast::<u8>();
+        unsafe { self.init_with_nonnull(NonNull::slice_from_raw_parts(ptr, len)) }
+    }
+
+    /// Initialize the heap with the memory `mem` points to.
+    ///
+    /// # Safety
+    ///
+    /// `mem` must be valid for reads and writes for as long as the heap is used.
+    pub unsafe fn init_with_nonnull(&self, mem: NonNull<[u8]>) -> Result<(), AlreadyInitialized> {
+        let s = &self.storage;
+        if !s.set(mem.cast(), mem.len()) {
+            return Err(AlreadyInitialized);
+        }
+        #[cfg(feature = "stats")]
+        self.stats.reset(mem.len());
+        self.remained.store(mem.len(), Ordering::Release);
+        Ok(())
+    }
+
+    /// # Safety
+    ///
+    /// This function is safe if the following invariants hold:
+    ///
+    /// - `address` points to valid memory.
+    /// - `size` is correct.
+    /// - Call it only once.
+    pub unsafe fn init_with_ptr(&self, address: usize, size: usize) {
+        let ptr = unsafe { NonNull::new_unchecked(address as *mut u8) };
+        let r = unsafe { self.init_with_nonnull(NonNull::slice_from_raw_parts(ptr, size)) };
+        debug_assert!(r.is_ok(), "{}", AlreadyInitialized);
+    }
+
+    /// Attach more memory to a running heap, it's used once the current memory runs out.
+    ///
+    /// Initializes the heap if that hasn't happened yet. Otherwise the start of the
+    /// new memory holds a small header that links it to the heap, and nothing
+    /// allocated before is moved.
+    ///
+    /// The new memory is always bumped [`Downward`], so blocks allocated from it
+    /// don't grow in place even if the heap is [`Upward`].
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be valid for reads and writes of `size` bytes for as long as the heap is used.
+    pub unsafe fn extend(&self, ptr: NonNull<u8>, size: usize) -> Result<(), RegionTooSmall> {
+        if unsafe { self.init_with_nonnull(NonNull::slice_from_raw_parts(ptr, size)) }.is_ok() {
+            return Ok(());
+        }
+
+        let start = ptr.as_ptr() as usize;
+        let header = start.next_multiple_of(align_of::<Heap<Pointer>>()) - start;
+        let front = header + size_of::<Heap<Pointer>>();
+        if front >= size {
+            return Err(RegionTooSmall);
+        }
+        let heap = unsafe { ptr.byte_add(header) }.cast::<Heap<Pointer>>();
+        unsafe { heap.write(Heap::empty()) };
+        let heap = unsafe { &*heap.as_ptr() };
+        let mem = NonNull::slice_from_raw_parts(unsafe { ptr.byte_add(front) }, size - front);
+        let _ = unsafe { heap.init_with_nonnull(mem) };
+
+        // Append it to the last extension.
+        let mut next = &self.storage.next;
+        loop {
+            match next.compare_exchange(
+                ptr::null_mut(),
+                heap as *const Heap<Pointer> as *mut Heap<Pointer>,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Ok(()),
+                Err(p) => next = &unsafe { &*p }.storage.next,
+            }
+        }
+    }
+}
+
+unsafe impl<S: Storage, D: Direction> GlobalAlloc for Heap<S, D> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.bump_extended(layout, None) {
+            Ok(p) => p.as_ptr(),
+            Err(Exhausted::Heap(remained)) => self.out_of_memory(layout, remained),
+            Err(Exhausted::Quota) => ptr::null_mut(),
+        }
+    }
+
+    /// Only the most recent allocation can be given back, because it is the only one
+    /// that sits right at the bump position. Deallocating anything else leaks it.
+    ///
+    /// Padding inserted for alignment is given back too, as long as the block is one of
+    /// the 8 most recent allocations that needed padding. On 32-bit targets it also takes
+    /// padding under 256 bytes, and less than 16 MiB left unused right after the block
+    /// was allocated.
+    ///
+    /// With the `stats` feature, leaked blocks are counted in `Heap::leaked_bytes`.
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.free(ptr, layout);
     }
+
+    /// Shrinking always keeps the pointer, the freed tail is given back if the block
+    /// is the most recent allocation and the heap bumps [`Upward`].
+    ///
+    /// The most recent allocation grows without leaking the old block. It grows in place
+    /// when bumping [`Upward`], and is moved down otherwise. Any other block is copied,
+    /// and its old memory is leaked.
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let p = unsafe { NonNull::new_unchecked(ptr) };
+        if new_size <= layout.size() {
+            let _ = self.resize_top(p, layout, new_layout, false);
+            return ptr;
+        }
+        if let Some(p) = self.resize_top(p, layout, new_layout, true) {
+            return p.as_ptr();
+        }
+
+        let new_ptr = unsafe { GlobalAlloc::alloc(self, new_layout) };
+        if !new_ptr.is_null() {
+            unsafe { ptr::copy_nonoverlapping(ptr, new_ptr, layout.size()) };
+            self.release_moved(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+/// Implements `Allocator` for the heaps, `Allocator` and `AllocError` must be in scope.
+///
+/// `core::alloc::Allocator` and `allocator_api2::alloc::Allocator` have the same shape,
+/// so both features share this.
+#[cfg(any(feature = "allocator-api", feature = "allocator-api2"))]
+macro_rules! impl_allocator {
+    () => {
+        // `&Heap<S>` is covered by the blanket `impl Allocator for &A`.
+        unsafe impl<S: Storage, D: Direction> Allocator for Heap<S, D> {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                match self.try_alloc_layout(layout) {
+                    Some(p) => Ok(NonNull::slice_from_raw_parts(p, layout.size())),
+                    None => Err(AllocError),
+                }
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                if layout.size() != 0 {
+                    unsafe { GlobalAlloc::dealloc(self, ptr.as_ptr(), layout) }
+                }
+            }
+
+            unsafe fn grow(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, AllocError> {
+                if let Some(p) = self.resize_top(ptr, old_layout, new_layout, true) {
+                    return Ok(NonNull::slice_from_raw_parts(p, new_layout.size()));
+                }
+                let new = self.allocate(new_layout)?;
+                unsafe {
+                    ptr::copy_nonoverlapping(ptr.as_ptr(), new.cast().as_ptr(), old_layout.size())
+                };
+                if old_layout.size() != 0 {
+                    self.release_moved(ptr.as_ptr(), old_layout);
+                }
+                Ok(new)
+            }
+
+            unsafe fn grow_zeroed(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, AllocError> {
+                let new = unsafe { self.grow(ptr, old_layout, new_layout) }?;
+                unsafe {
+                    new.cast::<u8>()
+                        .add(old_layout.size())
+                        .write_bytes(0, new_layout.size() - old_layout.size());
+                }
+                Ok(new)
+            }
+
+            unsafe fn shrink(
+                &self,
+                ptr: NonNull<u8>,
+                old_layout: Layout,
+                new_layout: Layout,
+            ) -> Result<NonNull<[u8]>, AllocError> {
+                if let Some(p) = self.resize_top(ptr, old_layout, new_layout, true) {
+                    return Ok(NonNull::slice_from_raw_parts(p, new_layout.size()));
+                }
+                // The tail is simply left unused.
+                if (ptr.as_ptr() as usize).is_multiple_of(new_layout.align()) {
+                    return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()));
+                }
+                let new = self.allocate(new_layout)?;
+                unsafe {
+                    ptr::copy_nonoverlapping(ptr.as_ptr(), new.cast().as_ptr(), new_layout.size())
+                };
+                if old_layout.size() != 0 {
+                    self.release_moved(ptr.as_ptr(), old_layout);
+                }
+                Ok(new)
+            }
+        }
+
+        unsafe impl<S: Storage, D: Direction, F: Allocator> Allocator for Chain<Heap<S, D>, F> {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                if layout.size() == 0 {
+                    return Ok(NonNull::slice_from_raw_parts(dangling(layout), 0));
+                }
+                let remained = match self.alloc_primary(layout) {
+                    Ok(p) => return Ok(NonNull::slice_from_raw_parts(p, layout.size())),
+                    Err(remained) => remained,
+                };
+                self.fallback
+                    .allocate(layout)
+                    .inspect_err(|_| self.out_of_memory(layout, remained))
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                if layout.size() == 0 {
+                    return;
+                }
+                if self.primary_owns(ptr.as_ptr()) {
+                    unsafe { GlobalAlloc::dealloc(&self.primary, ptr.as_ptr(), layout) }
+                } else {
+                    unsafe { self.fallback.deallocate(ptr, layout) }
+                }
+            }
+        }
+
+        unsafe impl<S: Storage, D: Direction> Allocator for Budget<'_, S, D> {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                if layout.size() == 0 {
+                    return Ok(NonNull::slice_from_raw_parts(dangling(layout), 0));
+                }
+                match NonNull::new(unsafe { GlobalAlloc::alloc(self, layout) }) {
+                    Some(p) => Ok(NonNull::slice_from_raw_parts(p, layout.size())),
+                    None => Err(AllocError),
+                }
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                if layout.size() != 0 {
+                    unsafe { GlobalAlloc::dealloc(self, ptr.as_ptr(), layout) }
+                }
+            }
+        }
+    };
+}
+
+#[cfg(feature = "allocator-api")]
+mod allocator_api {
+    use super::*;
+    use core::alloc::{AllocError, Allocator};
+
+    impl_allocator!();
+}
+
+#[cfg(feature = "allocator-api2")]
+mod allocator_api2 {
+    use super::*;
+    use ::allocator_api2::alloc::{AllocError, Allocator};
+
+    impl_allocator!();
+}
+
+// ------------------------------------------------------------------
+
+/// Allocates from the primary heap first, and falls back to another allocator
+/// once the primary one runs out of memory.
+///
+/// An allocation counts as failed in the statistics and OOM hook of the primary heap
+/// only if the fallback allocator fails too, and not at all once the primary heap
+/// is handed off with [`Chain::hand_off`].
+///
+/// ```rust
+/// use heap1::{Chain, Heap, Inline, Pointer};
+///
+/// static HEAP: Chain<Heap<Inline<1024>>, Heap<Pointer>> = Chain::new(Heap::new(), Heap::empty());
+/// ```
+pub struct Chain<P, F> {
+    primary: P,
+    fallback: F,
+    /// The memory handed off to the fallback allocator.
+    handed_off: AtomicPtr<u8>,
+    handed_off_len: AtomicUsize,
+}
+
+impl<P, F> Chain<P, F> {
+    /// Create a new allocator chain.
+    pub const fn new(primary: P, fallback: F) -> Self {
+        Self {
+            primary,
+            fallback,
+            handed_off: AtomicPtr::new(ptr::null_mut()),
+            handed_off_len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns the primary allocator.
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    /// Returns the fallback allocator.
+    pub fn fallback(&self) -> &F {
+        &self.fallback
+    }
+}
+
+impl<S: Storage, D: Direction, F> Chain<Heap<S, D>, F> {
+    /// Hand the rest of the primary heap off, see [`Heap::take_remaining`].
+    ///
+    /// All later allocations go to the fallback allocator, which is typically
+    /// initialized with the returned memory. Everything allocated before stays valid.
+    ///
+    /// ```rust
+    /// use heap1::{Chain, Heap, Heap4, Inline, Pointer};
+    ///
+    /// #[global_allocator]
+    /// static HEAP: Chain<Heap<Inline<4096>>, Heap4<Pointer>> = Chain::new(Heap::new(), Heap4::empty());
+    ///
+    /// fn main() {
+    ///     // Allocate permanent objects during boot, then
+    ///     let mem = HEAP.hand_off().unwrap();
+    ///     HEAP.fallback().init(mem).unwrap();
+    /// }
+    /// ```
+    pub fn hand_off(&self) -> Option<&mut [MaybeUninit<u8>]> {
+        let mem = self.primary.take_remaining()?;
+        self.handed_off_len.store(mem.len(), Ordering::Relaxed);
+        self.handed_off
+            .store(mem.as_mut_ptr().cast(), Ordering::Release);
+        Some(mem)
+    }
+
+    /// Returns `true` once the primary heap has been handed off, it's not tried anymore.
+    fn is_handed_off(&self) -> bool {
+        !self.handed_off.load(Ordering::Acquire).is_null()
+    }
+
+    /// Allocate from the primary heap without reporting failures, unless it's been handed off.
+    ///
+    /// Returns the unused bytes of the primary heap on failure, if it was tried.
+    fn alloc_primary(&self, layout: Layout) -> Result<NonNull<u8>, Option<usize>> {
+        if self.is_handed_off() {
+            return Err(None);
+        }
+        match self.primary.bump_extended(layout, None) {
+            Ok(p) => Ok(p),
+            Err(Exhausted::Heap(remained)) => Err(Some(remained)),
+            Err(Exhausted::Quota) => Err(None),
+        }
+    }
+
+    /// Report a failure of both allocators to the primary heap, if it was tried.
+    fn out_of_memory(&self, layout: Layout, remained: Option<usize>) {
+        if let Some(remained) = remained {
+            self.primary.out_of_memory(layout, remained);
+        }
+    }
+
+    /// Returns `true` if `ptr` was allocated by the primary heap.
+    fn primary_owns(&self, ptr: *const u8) -> bool {
+        let start = self.handed_off.load(Ordering::Acquire).cast_const();
+        let handed_off = start..start.wrapping_add(self.handed_off_len.load(Ordering::Relaxed));
+        self.primary.owns(ptr) && !handed_off.contains(&ptr)
+    }
+}
+
+unsafe impl<S: Storage, D: Direction, F: GlobalAlloc> GlobalAlloc for Chain<Heap<S, D>, F> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let remained = match self.alloc_primary(layout) {
+            Ok(p) => return p.as_ptr(),
+            Err(remained) => remained,
+        };
+        let p = unsafe { self.fallback.alloc(layout) };
+        if p.is_null() {
+            self.out_of_memory(layout, remained);
+        }
+        p
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if self.primary_owns(ptr) {
+            unsafe { GlobalAlloc::dealloc(&self.primary, ptr, layout) }
+        } else {
+            unsafe { self.fallback.dealloc(ptr, layout) }
+        }
+    }
+}
+
+// ------------------------------------------------------------------
+
+/// The error returned when initializing a [`Heap`] that has been initialized before.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlreadyInitialized;
+
+impl fmt::Display for AlreadyInitialized {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("heap already initialized")
+    }
+}
+
+impl core::error::Error for AlreadyInitialized {}
+
+/// The error returned when the memory given to [`Heap::extend`] can't hold its header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionTooSmall;
+
+impl fmt::Display for RegionTooSmall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("memory region too small")
+    }
+}
+
+impl core::error::Error for RegionTooSmall {}
+
+// ------------------------------------------------------------------
+
+/// Details of a failed allocation, see [`Heap::set_oom_hook`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapOomInfo {
+    /// The requested layout.
+    pub layout: Layout,
+    /// Unused bytes at the time of the failure.
+    pub remained: usize,
+    /// Total amount of bytes.
+    pub capacity: usize,
+}
+
+impl fmt::Display for HeapOomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "out of memory: requested {} bytes aligned to {}, {} of {} bytes remained",
+            self.layout.size(),
+            self.layout.align(),
+            self.remained,
+            self.capacity
+        )
+    }
+}
+
+/// Why [`Heap::bump`] failed.
+#[derive(Clone, Copy)]
+enum Exhausted {
+    /// The heap ran out, with the unused bytes.
+    Heap(usize),
+    /// The [`Budget`] it was charged to ran out.
+    Quota,
+}
+
+/// The OOM hook and the most recent failure, an `align` of 0 means no failure yet.
+struct Oom {
+    hook: AtomicPtr<()>,
+    size: AtomicUsize,
+    align: AtomicUsize,
+    remained: AtomicUsize,
 }
 
-impl Heap<Pointer> {
-    /// Create an empty heap allocator
-    pub const fn empty() -> Self {
-        Self::new_with_storage(Pointer::empty(), 0)
+impl Oom {
+    const fn new() -> Self {
+        Self {
+            hook: AtomicPtr::new(ptr::null_mut()),
+            size: AtomicUsize::new(0),
+            align: AtomicUsize::new(0),
+            remained: AtomicUsize::new(0),
+        }
     }
 
-    /// # Safety
-    ///
-    /// This function is safe if the following invariants hold:
-    ///
-    /// - `start_addr` points to valid memory.
-    /// - `size` is correct.
-    /// - Call it only once.
-    pub unsafe fn init_with_ptr(&self, address: usize, size: usize) {
-        let s = unsafe { &mut *self.storage.get() };
-        s.ptr = unsafe { NonNull::new_unchecked(address as *mut u8) };
-        self.remained.store(size, Ordering::Release);
+    fn set_hook(&self, hook: fn(&HeapOomInfo)) {
+        self.hook.store(hook as *mut (), Ordering::Release);
     }
-}
 
-unsafe impl<S: Storage> GlobalAlloc for Heap<S> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // `Layout` contract forbids making a `Layout` with align=0, or align not power of 2.
-        // So we can safely use a mask to ensure alignment without worrying about UB.
-        let align_mask_to_round_down = !(layout.align() - 1);
+    /// Returns the most recent failure, `capacity` isn't recorded.
+    fn last(&self, capacity: usize) -> Option<HeapOomInfo> {
+        let align = self.align.load(Ordering::Acquire);
+        if align == 0 {
+            return None;
+        }
+        let size = self.size.load(Ordering::Relaxed);
+        Some(HeapOomInfo {
+            layout: unsafe { Layout::from_size_align_unchecked(size, align) },
+            remained: self.remained.load(Ordering::Relaxed),
+            capacity,
+        })
+    }
 
-        let mut old_remained = self.remained.load(Ordering::Relaxed);
-        loop {
-            if layout.size() > old_remained {
-                return ptr::null_mut();
-            }
+    /// Record the failure and call the hook.
+    fn report(&self, info: HeapOomInfo) {
+        self.size.store(info.layout.size(), Ordering::Relaxed);
+        self.remained.store(info.remained, Ordering::Relaxed);
+        self.align.store(info.layout.align(), Ordering::Release);
 
-            let remained = (old_remained - layout.size()) & align_mask_to_round_down;
-            match self.remained.compare_exchange_weak(
-                old_remained,
-                remained,
-                Ordering::SeqCst,
-                Ordering::Relaxed,
-            ) {
-                Err(x) => old_remained = x,
-                Ok(_) => {
-                    return unsafe { ((&mut *self.storage.get()).ptr().as_ptr()).add(remained) };
-                }
-            }
+        let hook = self.hook.load(Ordering::Acquire);
+        if !hook.is_null() {
+            let hook = unsafe { core::mem::transmute::<*mut (), fn(&HeapOomInfo)>(hook) };
+            hook(&info);
         }
     }
+}
+
+// ------------------------------------------------------------------
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {}
+/// A marker of the allocation position of a [`Heap`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    remained: usize,
+    #[cfg(feature = "stats")]
+    padding: usize,
 }
 
-#[cfg(feature = "allocator-api")]
-mod allocator_api {
-    use super::*;
-    use core::alloc::{AllocError, Allocator};
+/// A borrowed [`Heap`] that is rewound when dropped, see [`Heap::scope`].
+pub struct Scope<'a, S: Storage, D: Direction = Downward> {
+    heap: &'a Heap<S, D>,
+    checkpoint: Checkpoint,
+}
 
-    unsafe impl Allocator for Heap {
-        fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
-            match layout.size() {
-                0 => Ok(NonNull::slice_from_raw_parts(layout.dangling(), 0)),
-                size => self.alloc(layout).map_or(Err(AllocError), |allocation| {
-                    Ok(NonNull::slice_from_raw_parts(allocation, size))
-                }),
-            }
-        }
+impl<S: Storage, D: Direction> Deref for Scope<'_, S, D> {
+    type Target = Heap<S, D>;
 
-        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
-            if layout.size() != 0 {
-                self.dealloc(ptr.as_ptr(), layout);
-            }
-        }
+    fn deref(&self) -> &Heap<S, D> {
+        self.heap
+    }
+}
+
+impl<S: Storage, D: Direction> Drop for Scope<'_, S, D> {
+    fn drop(&mut self) {
+        unsafe { self.heap.rewind(self.checkpoint) }
+        self.heap.scoped.store(false, Ordering::Relaxed);
     }
 }
 
 // ------------------------------------------------------------------
 
 /// Trait for providing access to the storage
-pub trait Storage {
+///
+/// # Safety
+///
+/// The heap trusts the pointer and size for memory safety, implementations must ensure:
+///
+/// - [`Storage::ptr`] always returns the same pointer, and [`Storage::size`] the same size,
+///   except that both may change once from an empty storage of size 0.
+/// - The memory `ptr()` points to is valid for reads and writes of `size()` bytes for as
+///   long as the storage lives, and nothing else accesses it.
+/// - The heap writes through the pointer while `&self` is alive, so inline buffers must
+///   sit in an [`UnsafeCell`].
+pub unsafe trait Storage {
     /// Return a pointer of the underlying storage.
+    fn ptr(&self) -> NonNull<u8>;
+
+    /// Return the size of the underlying storage in bytes.
+    fn size(&self) -> usize;
+
+    /// Return the heap to continue with once this storage runs out, see [`Heap::extend`].
     ///
-    /// ## Safety
-    ///
-    /// Implementations of this function MUST always return the same pointer
-    /// for all calls.
-    unsafe fn ptr(&mut self) -> NonNull<u8>;
+    /// It's always a [`Downward`] heap, whichever way this storage is bumped.
+    fn extension(&self) -> Option<&Heap<Pointer>> {
+        None
+    }
 }
 
-pub trait ConstStorage: Storage {
+/// Storage that can be created in a `const` context.
+///
+/// # Safety
+///
+/// `SIZE` must equal [`Storage::size`] of `INIT`.
+pub unsafe trait ConstStorage: Storage {
     /// The default value of this type
     const INIT: Self;
-    /// The size of storage
+    /// The size of storage, the same as [`Storage::size`]
     const SIZE: usize;
 }
 
 // ------------------------------------------------------------------
 
 pub struct Inline<const SIZE: usize> {
-    buf: [MaybeUninit<u8>; SIZE],
+    buf: UnsafeCell<[MaybeUninit<u8>; SIZE]>,
 }
 
 #[allow(clippy::new_without_default)]
 impl<const SIZE: usize> Inline<SIZE> {
     pub const fn new() -> Self {
         Self {
-            buf: [MaybeUninit::uninit(); SIZE],
+            buf: UnsafeCell::new([MaybeUninit::uninit(); SIZE]),
+        }
+    }
+}
+
+unsafe impl<const SIZE: usize> ConstStorage for Inline<SIZE> {
+    const INIT: Self = Self::new();
+    const SIZE: usize = SIZE;
+}
+
+unsafe impl<const SIZE: usize> Storage for Inline<SIZE> {
+    #[inline]
+    fn ptr(&self) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(self.buf.get().cast()) }
+    }
+
+    #[inline]
+    fn size(&self) -> usize {
+        SIZE
+    }
+}
+
+// ------------------------------------------------------------------
+
+/// Inline storage whose buffer is aligned to `ALIGN` bytes.
+///
+/// `ALIGN` must be a power of two from 1 to 65536.
+pub struct InlineAligned<const SIZE: usize, const ALIGN: usize>
+where
+    Align<ALIGN>: Alignment,
+{
+    _align: [<Align<ALIGN> as Alignment>::Archetype; 0],
+    buf: UnsafeCell<[MaybeUninit<u8>; SIZE]>,
+}
+
+#[allow(clippy::new_without_default)]
+impl<const SIZE: usize, const ALIGN: usize> InlineAligned<SIZE, ALIGN>
+where
+    Align<ALIGN>: Alignment,
+{
+    pub const fn new() -> Self {
+        Self {
+            _align: [],
+            buf: UnsafeCell::new([MaybeUninit::uninit(); SIZE]),
         }
     }
 }
 
-impl<const SIZE: usize> ConstStorage for Inline<SIZE> {
+unsafe impl<const SIZE: usize, const ALIGN: usize> ConstStorage for InlineAligned<SIZE, ALIGN>
+where
+    Align<ALIGN>: Alignment,
+{
     const INIT: Self = Self::new();
     const SIZE: usize = SIZE;
 }
 
-impl<const SIZE: usize> Storage for Inline<SIZE> {
+unsafe impl<const SIZE: usize, const ALIGN: usize> Storage for InlineAligned<SIZE, ALIGN>
+where
+    Align<ALIGN>: Alignment,
+{
+    #[inline]
+    fn ptr(&self) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(self.buf.get().cast()) }
+    }
+
     #[inline]
-    unsafe fn ptr(&mut self) -> NonNull<u8> {
-        unsafe { NonNull::new_unchecked(self.buf.as_mut_ptr().cast()) }
+    fn size(&self) -> usize {
+        SIZE
     }
 }
 
+/// Selects a type with an alignment of `N` bytes.
+pub struct Align<const N: usize>;
+
+/// Implemented by [`Align`] for every supported alignment.
+pub trait Alignment {
+    /// A zero-sized type with the requested alignment.
+    type Archetype: Copy;
+}
+
+macro_rules! impl_alignment {
+    ($($name:ident = $n:literal),+ $(,)?) => {$(
+        #[doc(hidden)]
+        #[derive(Clone, Copy)]
+        #[repr(align($n))]
+        pub struct $name;
+
+        impl Alignment for Align<$n> {
+            type Archetype = $name;
+        }
+    )+};
+}
+
+impl_alignment!(
+    A1 = 1,
+    A2 = 2,
+    A4 = 4,
+    A8 = 8,
+    A16 = 16,
+    A32 = 32,
+    A64 = 64,
+    A128 = 128,
+    A256 = 256,
+    A512 = 512,
+    A1024 = 1024,
+    A2048 = 2048,
+    A4096 = 4096,
+    A8192 = 8192,
+    A16384 = 16384,
+    A32768 = 32768,
+    A65536 = 65536,
+);
+
 // ------------------------------------------------------------------
 
 pub struct Pointer {
-    ptr: NonNull<u8>,
+    ptr: AtomicPtr<u8>,
+    size: AtomicUsize,
+    next: AtomicPtr<Heap<Pointer>>,
 }
 
 impl Pointer {
     const fn empty() -> Self {
         Self {
-            ptr: NonNull::dangling(),
+            ptr: AtomicPtr::new(ptr::null_mut()),
+            size: AtomicUsize::new(0),
+            next: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Returns `true` if it has been set.
+    fn is_set(&self) -> bool {
+        !self.ptr.load(Ordering::Acquire).is_null()
+    }
+
+    /// Returns `false` if it has been set before.
+    fn set(&self, ptr: NonNull<u8>, size: usize) -> bool {
+        if self
+            .ptr
+            .compare_exchange(
+                ptr::null_mut(),
+                ptr.as_ptr(),
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return false;
         }
+        self.size.store(size, Ordering::Release);
+        true
+    }
+}
+
+unsafe impl ConstStorage for Pointer {
+    const INIT: Self = Self::empty();
+    const SIZE: usize = 0;
+}
+
+unsafe impl Storage for Pointer {
+    #[inline]
+    fn ptr(&self) -> NonNull<u8> {
+        NonNull::new(self.ptr.load(Ordering::Acquire)).unwrap_or(NonNull::dangling())
+    }
+
+    #[inline]
+    fn size(&self) -> usize {
+        self.size.load(Ordering::Acquire)
     }
+
+    #[inline]
+    fn extension(&self) -> Option<&Heap<Pointer>> {
+        let next = self.next.load(Ordering::Acquire);
+        unsafe { next.as_ref() }
+    }
+}
+
+// ------------------------------------------------------------------
+
+/// Storage borrowed from a parent heap, see [`Heap::split_off`].
+pub struct SubHeap<'a> {
+    ptr: NonNull<u8>,
+    size: usize,
+    _parent: PhantomData<&'a ()>,
 }
 
-impl Storage for Pointer {
+unsafe impl Send for SubHeap<'_> {}
+
+unsafe impl Storage for SubHeap<'_> {
     #[inline]
-    unsafe fn ptr(&mut self) -> NonNull<u8> {
+    fn ptr(&self) -> NonNull<u8> {
         self.ptr
     }
+
+    #[inline]
+    fn size(&self) -> usize {
+        self.size
+    }
 }
 
 // ------------------------------------------------------------------
 
+/// A buffer on the global heap, aligned to [`BoxedSlice::ALIGN`] bytes.
 pub struct BoxedSlice {
-    buf: Box<[MaybeUninit<u8>]>,
+    // Kept raw, a `Box` would claim unique access to memory the heap has handed out.
+    buf: NonNull<[MaybeUninit<u8>]>,
 }
 
+unsafe impl Send for BoxedSlice {}
+
 impl BoxedSlice {
-    /// Create a new BoxedSlice with capacity `len`.
-    fn new(size: usize) -> Self {
-        let mut v = Vec::with_capacity(size);
-        unsafe { v.set_len(size) }
+    /// The alignment of the buffer, so allocations don't depend on where it lands.
+    pub const ALIGN: usize = 16;
+
+    /// Create a new BoxedSlice with capacity `size`.
+    pub fn new(size: usize) -> Self {
+        let ptr = match Self::layout(size) {
+            Some(layout) => match NonNull::new(unsafe { global::alloc(layout) }) {
+                Some(p) => p,
+                None => handle_alloc_error(layout),
+            },
+            None => unsafe { NonNull::new_unchecked(ptr::without_provenance_mut(Self::ALIGN)) },
+        };
         Self {
-            buf: v.into_boxed_slice(),
+            buf: NonNull::slice_from_raw_parts(ptr.cast(), size),
+        }
+    }
+
+    /// Returns `None` for an empty buffer, which isn't allocated.
+    fn layout(size: usize) -> Option<Layout> {
+        if size == 0 {
+            return None;
+        }
+        Some(Layout::from_size_align(size, Self::ALIGN).expect("BoxedSlice too large"))
+    }
+}
+
+impl Drop for BoxedSlice {
+    fn drop(&mut self) {
+        if let Some(layout) = Self::layout(self.buf.len()) {
+            unsafe { global::dealloc(self.buf.cast().as_ptr(), layout) };
         }
     }
 }
 
-impl Storage for BoxedSlice {
+unsafe impl Storage for BoxedSlice {
+    #[inline]
+    fn ptr(&self) -> NonNull<u8> {
+        self.buf.cast()
+    }
+
     #[inline]
-    unsafe fn ptr(&mut self) -> NonNull<u8> {
-        unsafe { NonNull::new_unchecked(self.buf.as_mut_ptr().cast()) }
+    fn size(&self) -> usize {
+        self.buf.len()
     }
 }
 
@@ -222,32 +1451,40 @@ impl Storage for BoxedSlice {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(not(feature = "std"))]
+    use alloc::boxed::Box;
+    #[cfg(all(feature = "stats", not(feature = "std")))]
+    use alloc::format;
+    #[cfg(all(feature = "allocator-api", not(feature = "std")))]
+    use alloc::vec::Vec;
     static HEAP: Heap<Inline<100>> = Heap::new();
     static HEAP_P: Heap<Pointer> = Heap::empty();
 
     #[test]
     fn test_heap() {
         assert_eq!(HEAP.remained.load(Ordering::Relaxed), 100);
-        let p0 = unsafe { (&mut *HEAP.storage.get()).buf.as_ptr() as usize };
-        let p1 = unsafe { (&mut *HEAP.storage.get()).ptr() }.as_ptr();
+        let p0 = HEAP.storage.buf.get() as usize;
+        let p1 = HEAP.storage.ptr().as_ptr();
         assert_eq!(p0, p1 as usize);
-        let p2 = unsafe { HEAP.alloc(Layout::new::<u64>()) };
+        let p2 = unsafe { GlobalAlloc::alloc(&HEAP, Layout::new::<u64>()) };
         assert_eq!(HEAP.remained.load(Ordering::Relaxed), 88);
         assert_eq!(unsafe { p2.offset_from(p1) }, 88);
 
-        unsafe { HEAP.alloc(Layout::new::<u32>()) };
+        unsafe { GlobalAlloc::alloc(&HEAP, Layout::new::<u32>()) };
         assert_eq!(HEAP.remained.load(Ordering::Relaxed), 84);
     }
 
     #[test]
     fn test_heap_pointer() {
         const HEAP_SIZE: usize = 100;
-        static mut HEAP_MEM: [MaybeUninit<u8>; HEAP_SIZE] = [MaybeUninit::uninit(); HEAP_SIZE];
-        unsafe { HEAP_P.init_with_ptr(&raw mut HEAP_MEM as usize, HEAP_SIZE) }
+        // Aligned, so the offsets below are deterministic
+        static mut HEAP_MEM: [u64; HEAP_SIZE.div_ceil(8)] = [0; HEAP_SIZE.div_ceil(8)];
+        let mem = NonNull::new(&raw mut HEAP_MEM).unwrap().cast::<u8>();
+        unsafe { HEAP_P.init_with_nonnull(NonNull::slice_from_raw_parts(mem, HEAP_SIZE)) }.unwrap();
         let p0 = &raw mut HEAP_MEM as usize;
-        let p1 = unsafe { (&mut *HEAP_P.storage.get()).ptr() }.as_ptr();
+        let p1 = HEAP_P.storage.ptr().as_ptr();
         assert_eq!(p0, p1 as usize);
-        let p2 = unsafe { HEAP_P.alloc(Layout::new::<u64>()) };
+        let p2 = unsafe { GlobalAlloc::alloc(&HEAP_P, Layout::new::<u64>()) };
         assert_eq!(HEAP_P.remained.load(Ordering::Relaxed), 88);
         assert_eq!(unsafe { p2.offset_from(p1) }, 88);
     }
@@ -256,19 +1493,682 @@ mod tests {
     fn test_heap_dynamic() {
         let heap = Heap::new_boxed(100);
         assert_eq!(heap.remained.load(Ordering::Relaxed), 100);
-        let p0 = unsafe { (&mut *heap.storage.get()).buf.as_ptr() as usize };
-        let p1 = unsafe { (&mut *heap.storage.get()).ptr() }.as_ptr();
+        let p0 = heap.storage.buf.cast::<u8>().as_ptr() as usize;
+        let p1 = heap.storage.ptr().as_ptr();
         assert_eq!(p0, p1 as usize);
-        let p2 = unsafe { heap.alloc(Layout::new::<u64>()) };
+        let p2 = unsafe { GlobalAlloc::alloc(&heap, Layout::new::<u64>()) };
         assert_eq!(heap.remained.load(Ordering::Relaxed), 88);
         assert_eq!(unsafe { p2.offset_from(p1) }, 88);
 
-        unsafe { heap.alloc(Layout::new::<u32>()) };
+        unsafe { GlobalAlloc::alloc(&heap, Layout::new::<u32>()) };
         assert_eq!(heap.remained.load(Ordering::Relaxed), 84);
     }
 
+    #[test]
+    fn test_heap_dealloc() {
+        let heap: Heap<Inline<100>> = Heap::new();
+        let l0 = Layout::new::<[u8; 10]>();
+        let l1 = Layout::new::<[u8; 20]>();
+        let p0 = unsafe { GlobalAlloc::alloc(&heap, l0) };
+        let p1 = unsafe { GlobalAlloc::alloc(&heap, l1) };
+        assert_eq!(heap.remained(), 70);
+
+        unsafe { heap.dealloc(p1, l1) };
+        assert_eq!(heap.remained(), 90);
+        unsafe { heap.dealloc(p0, l0) };
+        assert_eq!(heap.remained(), 100);
+
+        // Not the most recent allocation, nothing happens
+        let p2 = unsafe { GlobalAlloc::alloc(&heap, l0) };
+        unsafe { GlobalAlloc::alloc(&heap, l1) };
+        unsafe { heap.dealloc(p2, l0) };
+        assert_eq!(heap.remained(), 70);
+    }
+
+    #[test]
+    fn test_heap_checkpoint() {
+        let mut heap: Heap<Inline<100>> = Heap::new();
+        let cp = heap.checkpoint();
+        unsafe { GlobalAlloc::alloc(&heap, Layout::new::<u32>()) };
+        assert_eq!(heap.remained(), 96);
+        unsafe { heap.rewind(cp) };
+        assert_eq!(heap.remained(), 100);
+
+        let r = heap.scope(|s| {
+            s.alloc(0u32);
+            assert_eq!(s.remained(), 96);
+            100 - s.remained()
+        });
+        assert_eq!(r, 4);
+        assert_eq!(heap.remained(), 100);
+    }
+
+    #[test]
+    fn test_heap_typed() {
+        let heap: Heap<Inline<100>> = Heap::new();
+        let a = heap.alloc(1u32);
+        let b = heap.alloc_with(|| [2u16; 3]);
+        *a += 1;
+        assert_eq!(*a, 2);
+        assert_eq!(*b, [2; 3]);
+        assert_eq!(heap.remained(), 90);
+
+        let s = heap.alloc_slice_copy(&[1u8, 2, 3]);
+        assert_eq!(s, &[1, 2, 3]);
+        let s = heap.alloc_slice_fill_with(4, |i| i as u8);
+        assert_eq!(s, &[0, 1, 2, 3]);
+        let s = heap.alloc_str("heap");
+        s.make_ascii_uppercase();
+        assert_eq!(s, "HEAP");
+        assert_eq!(heap.remained(), 79);
+
+        heap.alloc(());
+        assert_eq!(heap.remained(), 79);
+    }
+
+    #[test]
+    fn test_heap_static() {
+        static HEAP: Heap<Inline<64>> = Heap::new();
+        let a: &'static mut u32 = HEAP.alloc_static(1).unwrap();
+        *a += 1;
+        assert_eq!(*a, 2);
+        let b = HEAP.alloc_static_uninit::<u64>().unwrap();
+        b.write(3);
+        let buf: &'static mut [u8] = HEAP.alloc_static_slice(16, 0).unwrap();
+        assert_eq!(buf, &[0; 16]);
+        let buf = HEAP.alloc_static_uninit_slice::<u8>(20).unwrap();
+        assert_eq!(buf.len(), 20);
+        assert_eq!(HEAP.remained(), 12);
+        assert!(HEAP.alloc_static([0u8; 13]).is_none());
+        assert!(HEAP.alloc_static_slice(usize::MAX, 0u64).is_none());
+    }
+
+    #[test]
+    fn test_heap_align() {
+        let mut mem = [0u64; 16];
+        let heap: Heap<Pointer> = Heap::empty();
+        // Deliberately misaligned base
+        let ptr = unsafe { NonNull::from(&mut mem).cast::<u8>().add(1) };
+        let base = ptr.as_ptr() as usize;
+        unsafe { heap.init_with_nonnull(NonNull::slice_from_raw_parts(ptr, 100)) }.unwrap();
+
+        let p = unsafe { GlobalAlloc::alloc(&heap, Layout::new::<u64>()) };
+        assert_eq!(p as usize % 8, 0);
+        assert!(p as usize >= base && p as usize + 8 <= base + 100);
+        assert_eq!(heap.remained(), p as usize - base);
+        let p = unsafe { GlobalAlloc::alloc(&heap, Layout::new::<u16>()) };
+        assert_eq!(p as usize % 2, 0);
+
+        let heap: Heap<Pointer> = Heap::empty();
+        unsafe { heap.init_with_nonnull(NonNull::slice_from_raw_parts(ptr, 8)) }.unwrap();
+        // Fits by size but not once aligned
+        let p = unsafe { GlobalAlloc::alloc(&heap, Layout::new::<u64>()) };
+        assert!(p.is_null());
+        assert_eq!(heap.remained(), 8);
+
+        let heap: Heap<Inline<8192>> = Heap::new();
+        let layout = Layout::from_size_align(1, 4096).unwrap();
+        let p = unsafe { GlobalAlloc::alloc(&heap, layout) };
+        assert_eq!(p as usize % 4096, 0);
+    }
+
+    #[test]
+    fn test_heap_inline_aligned() {
+        static HEAP: Heap<InlineAligned<256, 32>> = Heap::new();
+        let base = HEAP.storage.ptr().as_ptr();
+        assert_eq!(base as usize % 32, 0);
+        let layout = Layout::from_size_align(32, 32).unwrap();
+        let p = unsafe { GlobalAlloc::alloc(&HEAP, layout) };
+        assert_eq!(HEAP.remained(), 224);
+        assert_eq!(p as usize % 32, 0);
+
+        let heap: Heap<InlineAligned<4096, 4096>> = Heap::new();
+        let base = heap.storage.ptr().as_ptr();
+        assert_eq!(base as usize % 4096, 0);
+    }
+
+    #[test]
+    fn test_heap_from_storage() {
+        let heap: Heap<_> = Heap::from_storage(Inline::<100>::new());
+        assert_eq!(heap.remained(), 100);
+        let heap: Heap<_> = Heap::from_storage(BoxedSlice::new(64));
+        assert_eq!(heap.remained(), 64);
+        let heap: Heap<Pointer> = Heap::empty();
+        assert_eq!(heap.storage.size(), 0);
+        let mut mem = [0u8; 32];
+        unsafe { heap.init_with_nonnull(NonNull::from(&mut mem[..])) }.unwrap();
+        assert_eq!(heap.storage.size(), 32);
+    }
+
+    #[test]
+    fn test_heap_init() {
+        static HEAP: Heap<Pointer> = Heap::empty();
+        let mem: &'static mut [MaybeUninit<u8>] = Box::leak(Box::new([MaybeUninit::uninit(); 64]));
+        let base = mem.as_ptr() as usize;
+        assert_eq!(HEAP.init(mem), Ok(()));
+        assert_eq!(HEAP.remained(), 64);
+
+        let mem2: &'static mut [MaybeUninit<u8>] = Box::leak(Box::new([MaybeUninit::uninit(); 64]));
+        let len = mem2.len();
+        let p2 = NonNull::from(&mut *mem2).cast::<u8>();
+        assert_eq!(HEAP.init(mem2), Err(AlreadyInitialized));
+        let r = unsafe { HEAP.init_with_nonnull(NonNull::slice_from_raw_parts(p2, len)) };
+        assert_eq!(r, Err(AlreadyInitialized));
+        let p = HEAP.storage.ptr();
+        assert_eq!(p.as_ptr() as usize, base);
+    }
+
+    #[test]
+    fn test_heap_usage() {
+        let heap: Heap<Inline<100>> = Heap::new();
+        assert_eq!(heap.capacity(), 100);
+        assert_eq!(heap.used(), 0);
+        heap.alloc(0u32);
+        assert_eq!(heap.used(), 4);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_heap_stats() {
+        let heap: Heap<Inline<100>> = Heap::new();
+        let l = Layout::new::<[u8; 40]>();
+        let p = unsafe { GlobalAlloc::alloc(&heap, l) };
+        heap.alloc(0u32);
+        let big = Layout::new::<[u8; 57]>();
+        assert!(unsafe { GlobalAlloc::alloc(&heap, big) }.is_null());
+        assert_eq!(heap.min_ever_remained(), 56);
+        assert_eq!(heap.allocation_count(), 2);
+        assert_eq!(heap.failed_allocation_count(), 1);
+
+        unsafe { GlobalAlloc::dealloc(&heap, p, l) };
+        assert_eq!(heap.min_ever_remained(), 56);
+        assert_eq!(heap.leaked_bytes(), 40);
+        assert_eq!(heap.leak_count(), 1);
+        let p = unsafe { GlobalAlloc::alloc(&heap, l) };
+        unsafe { GlobalAlloc::dealloc(&heap, p, l) };
+        assert_eq!(heap.leaked_bytes(), 40);
+        assert_eq!(heap.leak_count(), 1);
+
+        let heap: Heap<Pointer> = Heap::empty();
+        assert_eq!(heap.min_ever_remained(), 0);
+        heap.init(Box::leak(Box::new([MaybeUninit::uninit(); 64])))
+            .unwrap();
+        assert_eq!(heap.min_ever_remained(), 64);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_heap_stats_snapshot() {
+        let mut heap: Heap<InlineAligned<100, 8>> = Heap::new();
+        let a: *mut u8 = heap.alloc(0u8);
+        let b: *mut u32 = heap.alloc(0u32);
+        let stats = heap.stats();
+        assert_eq!(
+            stats,
+            HeapStats {
+                capacity: 100,
+                used: 8,
+                remaining: 92,
+                peak: 8,
+                alloc_count: 2,
+                failed_count: 0,
+                padding: 3,
+                realloc_lost: 0,
+                leak_count: 0,
+                leaked: 0,
+            }
+        );
+        assert_eq!(
+            format!("{stats}"),
+            "capacity: 100, used: 8, remaining: 92, peak: 8, allocs: 2, failed: 0, padding: 3, realloc lost: 0, leaks: 0, leaked: 0"
+        );
+
+        // Padding given back with its block no longer counts
+        unsafe {
+            GlobalAlloc::dealloc(&heap, b.cast(), Layout::new::<u32>());
+            GlobalAlloc::dealloc(&heap, a, Layout::new::<u8>());
+        }
+        assert_eq!((heap.stats().used, heap.stats().padding), (0, 0));
+        heap.scope(|s| {
+            s.alloc(0u8);
+            s.alloc(0u32);
+            assert_eq!(s.stats().padding, 3);
+        });
+        assert_eq!(heap.stats().padding, 0);
+    }
+
+    #[test]
+    fn test_heap_oom() {
+        static FAILED: AtomicUsize = AtomicUsize::new(0);
+        fn hook(info: &HeapOomInfo) {
+            assert_eq!(info.capacity, 16);
+            FAILED.store(info.layout.size(), Ordering::Relaxed);
+        }
+
+        let heap: Heap<Inline<16>> = Heap::new();
+        let l = Layout::new::<[u8; 20]>();
+        assert!(unsafe { GlobalAlloc::alloc(&heap, l) }.is_null());
+        assert_eq!(FAILED.load(Ordering::Relaxed), 0);
+        let info = heap.last_oom().unwrap();
+        assert_eq!(info.layout, l);
+        assert_eq!(info.remained, 16);
+
+        heap.set_oom_hook(hook);
+        heap.alloc(0u64);
+        assert!(heap.last_oom().is_some());
+        let l = Layout::new::<[u64; 2]>();
+        assert!(unsafe { GlobalAlloc::alloc(&heap, l) }.is_null());
+        assert_eq!(FAILED.load(Ordering::Relaxed), 16);
+        assert_eq!(heap.last_oom().unwrap().remained, 8);
+    }
+
+    #[test]
+    fn test_chain() {
+        static FAILED: AtomicUsize = AtomicUsize::new(0);
+        let chain = Chain::new(Heap::<Inline<16>>::new(), Heap::<Inline<64>>::new());
+        chain.primary().set_oom_hook(|_| {
+            FAILED.fetch_add(1, Ordering::Relaxed);
+        });
+        let l = Layout::new::<[u8; 12]>();
+        let p0 = unsafe { chain.alloc(l) };
+        let p1 = unsafe { chain.alloc(l) };
+        assert!(chain.primary().owns(p0));
+        assert!(!chain.primary().owns(p1));
+        assert!(chain.fallback().owns(p1));
+        assert_eq!(chain.fallback().remained(), 52);
+
+        // Falling back isn't a failure, only failing in both is
+        assert_eq!(FAILED.load(Ordering::Relaxed), 0);
+        assert_eq!(chain.primary().last_oom(), None);
+        let big = Layout::new::<[u8; 64]>();
+        assert!(unsafe { chain.alloc(big) }.is_null());
+        assert_eq!(FAILED.load(Ordering::Relaxed), 1);
+        assert_eq!(chain.primary().last_oom().unwrap().remained, 4);
+        #[cfg(feature = "stats")]
+        assert_eq!(chain.primary().failed_allocation_count(), 1);
+
+        unsafe { chain.dealloc(p1, l) };
+        assert_eq!(chain.fallback().remained(), 64);
+        unsafe { chain.dealloc(p0, l) };
+        assert_eq!(chain.primary().remained(), 16);
+    }
+
+    #[test]
+    fn test_heap_range() {
+        fn check<S: Storage>(heap: &Heap<S>) {
+            assert_eq!(heap.end() as usize - heap.base() as usize, 64);
+            assert_eq!(heap.used_range(), heap.end()..heap.end());
+            let p = heap.alloc(0u32) as *const u32 as *const u8;
+            assert_eq!(heap.used_range(), p..heap.end());
+            assert!(heap.owns(p) && heap.contains(p));
+            assert!(heap.owns(heap.base()) && !heap.contains(heap.base()));
+            assert!(!heap.owns(heap.end()) && !heap.contains(heap.end()));
+            assert!(!heap.owns(&0u8));
+        }
+
+        check(&Heap::<InlineAligned<64, 4>>::new());
+        check(&Heap::new_boxed(64));
+        let heap: Heap<Pointer> = Heap::empty();
+        assert!(!heap.owns(heap.base()));
+        heap.init(Box::leak(Box::new([MaybeUninit::uninit(); 64])))
+            .unwrap();
+        check(&heap);
+    }
+
+    #[test]
+    fn test_heap_extend() {
+        let heap: Heap<Pointer> = Heap::empty();
+        let mut mem0 = [0u64; 4];
+        let mut mem1 = [0u64; 32];
+        let mut mem2 = [0u64; 64];
+        let ptr = |m: &mut [u64]| NonNull::from(m).cast::<u8>();
+        let (a1, a2) = (mem1.as_ptr() as usize, mem2.as_ptr() as usize);
+        unsafe { heap.extend(ptr(&mut mem0), 32) }.unwrap();
+        assert_eq!(heap.capacity(), 32);
+        let l = Layout::new::<[u64; 3]>();
+        let p0 = unsafe { GlobalAlloc::alloc(&heap, l) };
+        assert!(heap.contains(p0));
+
+        assert_eq!(
+            unsafe { heap.extend(ptr(&mut mem1), size_of::<Heap<Pointer>>()) },
+            Err(RegionTooSmall)
+        );
+        unsafe { heap.extend(ptr(&mut mem1), 256) }.unwrap();
+        unsafe { heap.extend(ptr(&mut mem2), 512) }.unwrap();
+        let header = size_of::<Heap<Pointer>>();
+        assert_eq!(heap.capacity(), 32 + 768 - 2 * header);
+        assert_eq!(heap.remained(), 8 + 768 - 2 * header);
+
+        // Served by the first extension, earlier pointers stay where they are
+        let p1 = unsafe { GlobalAlloc::alloc(&heap, l) };
+        assert!(heap.owns(p1) && !heap.contains(p1));
+        assert!((a1..a1 + 256).contains(&(p1 as usize)));
+        assert!(heap.owns(p0) && heap.contains(p0));
+        let big = Layout::new::<[u64; 30]>();
+        let p2 = unsafe { GlobalAlloc::alloc(&heap, big) };
+        assert!((a2..a2 + 512).contains(&(p2 as usize)));
+
+        unsafe { GlobalAlloc::dealloc(&heap, p2, big) };
+        unsafe { GlobalAlloc::dealloc(&heap, p1, l) };
+        assert_eq!(heap.remained(), 8 + 768 - 2 * header);
+    }
+
+    #[test]
+    fn test_heap_scope_extended() {
+        let mut heap: Heap<Pointer> = Heap::empty();
+        let mut mem0 = [0u64; 2];
+        let mut mem1 = [0u64; 128];
+        let ptr = |m: &mut [u64]| NonNull::from(m).cast::<u8>();
+        unsafe { heap.extend(ptr(&mut mem0), 16) }.unwrap();
+        unsafe { heap.extend(ptr(&mut mem1), 1024) }.unwrap();
+        let remained = heap.remained();
+
+        // Extensions aren't rewound, so they aren't used inside a scope
+        let l = Layout::new::<[u8; 64]>();
+        heap.scope(|s| {
+            assert!(unsafe { GlobalAlloc::alloc(&**s, l) }.is_null());
+            s.alloc(0u64);
+        });
+        assert_eq!(heap.remained(), remained);
+        assert!(!unsafe { GlobalAlloc::alloc(&heap, l) }.is_null());
+        assert_eq!(heap.remained(), remained - 64);
+    }
+
+    #[test]
+    fn test_heap_take_remaining() {
+        let chain = Chain::new(Heap::<InlineAligned<256, 16>>::new(), Heap4::empty());
+        let l = Layout::new::<[u64; 2]>();
+        let p0 = unsafe { chain.alloc(l) };
+        let mem = chain.hand_off().unwrap();
+        assert_eq!(mem.len(), 240);
+        assert_eq!(chain.primary().remained(), 0);
+        let mem = NonNull::slice_from_raw_parts(NonNull::from(&mut *mem).cast::<u8>(), mem.len());
+        assert!(chain.hand_off().is_none());
+        unsafe { chain.fallback().init_with_nonnull(mem) }.unwrap();
+
+        // The exhausted primary heap isn't tried anymore
+        chain
+            .primary()
+            .set_oom_hook(|_| panic!("primary heap tried after hand-off"));
+        let p1 = unsafe { chain.alloc(l) };
+        assert!(chain.primary().owns(p1));
+        assert!(!chain.primary_owns(p1));
+        assert_eq!(chain.primary().last_oom(), None);
+        #[cfg(feature = "stats")]
+        assert_eq!(chain.primary().failed_allocation_count(), 0);
+        unsafe { chain.dealloc(p1, l) };
+        assert_eq!(unsafe { chain.alloc(l) }, p1);
+        unsafe { chain.dealloc(p0, l) };
+        assert_eq!(chain.primary().remained(), 0);
+    }
+
+    #[test]
+    fn test_heap_split_off() {
+        let heap: Heap<InlineAligned<256, 8>> = Heap::new();
+        let net = heap.split_off(100).unwrap();
+        let ui = heap.split_off(50).unwrap();
+        assert_eq!(heap.remained(), 96);
+        assert!(heap.split_off(200).is_none());
+        assert_eq!(net.remained(), 100);
+        assert_eq!(ui.capacity(), 50);
+
+        let buf = net.alloc_slice_fill_with(100, |_| 0u8);
+        assert!(heap.contains(buf.as_ptr()));
+        assert!(net.owns(buf.as_ptr()) && !ui.owns(buf.as_ptr()));
+        assert_eq!(net.remained(), 0);
+        assert!(net.split_off(1).is_none());
+
+        // The others are not affected
+        ui.alloc(0u32);
+        assert_eq!(ui.remained(), 44);
+        assert_eq!(heap.remained(), 96);
+    }
+
     #[test]
     fn test_heap_local() {
         let _heap: Heap<Inline<100>> = Heap::new();
     }
+
+    #[test]
+    fn test_heap_upward() {
+        let heap: Heap<InlineAligned<64, 8>, Upward> = Heap::new();
+        let base = heap.base();
+        let p0 = unsafe { GlobalAlloc::alloc(&heap, Layout::new::<u32>()) };
+        let p1 = unsafe { GlobalAlloc::alloc(&heap, Layout::new::<u64>()) };
+        assert_eq!(p0.cast_const(), base);
+        assert_eq!(p1.cast_const(), base.wrapping_add(8));
+        assert_eq!(heap.remained(), 48);
+        assert_eq!(heap.used_range(), base..base.wrapping_add(16));
+        assert!(heap.contains(p1) && !heap.contains(base.wrapping_add(16)));
+
+        unsafe { heap.dealloc(p1, Layout::new::<u64>()) };
+        assert_eq!(heap.remained(), 60);
+        unsafe { heap.dealloc(p0, Layout::new::<u32>()) };
+        assert_eq!(heap.remained(), 64);
+
+        let p2 = unsafe { GlobalAlloc::alloc(&heap, Layout::new::<u64>()) };
+        assert_eq!(p2.cast_const(), base);
+        let mem = heap.take_remaining().unwrap();
+        assert_eq!(mem.as_ptr().cast(), base.wrapping_add(8));
+        assert_eq!(mem.len(), 56);
+    }
+
+    #[test]
+    fn test_heap_upward_dynamic() {
+        let heap = Heap::<_, Upward>::from_storage(BoxedSlice::new(64));
+        let base = heap.base();
+        let p = unsafe { GlobalAlloc::alloc(&heap, Layout::new::<u64>()) };
+        assert_eq!(p.cast_const(), base);
+
+        let heap: Heap<Pointer, Upward> = Heap::new();
+        let mem: &'static mut [MaybeUninit<u8>] = Box::leak(Box::new([MaybeUninit::uninit(); 64]));
+        assert_eq!(heap.init(mem), Ok(()));
+        let p = unsafe { GlobalAlloc::alloc(&heap, Layout::new::<u8>()) };
+        assert_eq!(p.cast_const(), heap.base());
+    }
+
+    fn check_reverse_dealloc<D: Direction>() {
+        let heap: Heap<InlineAligned<256, 16>, D> = Heap::from_storage(InlineAligned::new());
+        let layouts = [
+            Layout::new::<u8>(),
+            Layout::new::<u32>(),
+            Layout::new::<u8>(),
+            Layout::new::<u16>(),
+            Layout::new::<u64>(),
+            Layout::new::<[u8; 3]>(),
+            Layout::from_size_align(16, 16).unwrap(),
+            Layout::new::<u8>(),
+            Layout::new::<u32>(),
+        ];
+        let blocks = layouts.map(|l| {
+            let remained = heap.remained();
+            (unsafe { GlobalAlloc::alloc(&heap, l) }, l, remained)
+        });
+        assert!(heap.remained() < 256 - layouts.iter().map(Layout::size).sum::<usize>());
+        for (p, l, remained) in blocks.into_iter().rev() {
+            unsafe { heap.dealloc(p, l) };
+            assert_eq!(heap.remained(), remained);
+        }
+        assert_eq!(heap.remained(), 256);
+    }
+
+    #[test]
+    fn test_heap_reverse_dealloc() {
+        check_reverse_dealloc::<Downward>();
+        check_reverse_dealloc::<Upward>();
+    }
+
+    #[test]
+    fn test_heap_realloc() {
+        let l = Layout::new::<[u64; 2]>();
+
+        // Grows in place
+        let heap: Heap<InlineAligned<64, 8>, Upward> = Heap::new();
+        let p = unsafe { GlobalAlloc::alloc(&heap, l) };
+        unsafe { p.write(7) };
+        assert_eq!(unsafe { heap.realloc(p, l, 48) }, p);
+        assert_eq!(heap.remained(), 16);
+        assert!(unsafe { heap.realloc(p, Layout::new::<[u64; 6]>(), 72) }.is_null());
+
+        // Moves down without leaking
+        let heap: Heap<InlineAligned<64, 8>> = Heap::new();
+        let p = unsafe { GlobalAlloc::alloc(&heap, l) };
+        unsafe { p.write(7) };
+        let q = unsafe { heap.realloc(p, l, 48) };
+        assert_eq!(q, p.wrapping_sub(32));
+        assert_eq!(unsafe { q.read() }, 7);
+        assert_eq!(heap.remained(), 16);
+
+        // Shrinks in place
+        assert_eq!(unsafe { heap.realloc(q, Layout::new::<[u64; 6]>(), 8) }, q);
+        assert_eq!(heap.remained(), 16);
+        let heap: Heap<InlineAligned<64, 8>, Upward> = Heap::new();
+        let p = unsafe { GlobalAlloc::alloc(&heap, l) };
+        assert_eq!(unsafe { heap.realloc(p, l, 8) }, p);
+        assert_eq!(heap.remained(), 56);
+
+        // Not the most recent allocation, copied
+        let heap: Heap<InlineAligned<64, 8>, Upward> = Heap::new();
+        let p = unsafe { GlobalAlloc::alloc(&heap, l) };
+        unsafe { GlobalAlloc::alloc(&heap, l) };
+        let q = unsafe { heap.realloc(p, l, 24) };
+        assert_eq!(q, p.wrapping_add(32));
+        assert_eq!(heap.remained(), 8);
+        #[cfg(feature = "stats")]
+        assert_eq!(heap.realloc_lost_bytes(), 16);
+    }
+
+    #[cfg(feature = "allocator-api")]
+    fn check_allocator<S: Storage>(heap: &Heap<S>) {
+        let capacity = heap.remained();
+        let mut v = Vec::new_in(heap);
+        v.extend_from_slice(&[1u32, 2, 3]);
+        let p = v.as_ptr();
+        // The topmost block grows without leaking the old one
+        v.reserve_exact(5);
+        assert_ne!(v.as_ptr(), p);
+        assert_eq!(v, [1, 2, 3]);
+        // The storage may have any alignment
+        let base = heap.base() as usize;
+        assert_eq!(heap.remained(), (base + capacity - 8 * 4) / 4 * 4 - base);
+        v.shrink_to_fit();
+        assert_eq!(v, [1, 2, 3]);
+        drop(v);
+        assert_eq!(heap.remained(), capacity);
+
+        let b = Box::new_in(7u64, heap);
+        assert_eq!(*b, 7);
+        let z = Box::new_in((), heap);
+        drop(z);
+        drop(b);
+        assert_eq!(heap.remained(), capacity);
+    }
+
+    #[cfg(feature = "allocator-api")]
+    #[test]
+    fn test_heap_allocator_api() {
+        let heap: Heap<InlineAligned<128, 8>> = Heap::new();
+        check_allocator(&heap);
+
+        let heap: Heap<Inline<128>> = Heap::new();
+        check_allocator(&heap);
+
+        static HEAP: Heap<Pointer> = Heap::empty();
+        HEAP.init(Box::leak(Box::new([MaybeUninit::uninit(); 128])))
+            .unwrap();
+        check_allocator(&HEAP);
+
+        check_allocator(&Heap::new_boxed(128));
+
+        // Growing in place costs no extra memory
+        let heap: Heap<InlineAligned<128, 8>, Upward> = Heap::new();
+        let mut v = Vec::new_in(&heap);
+        for i in 0..32u32 {
+            v.push(i);
+        }
+        assert_eq!(heap.used(), 128);
+    }
+
+    #[cfg(feature = "allocator-api2")]
+    #[test]
+    fn test_heap_allocator_api2() {
+        use ::allocator_api2::{boxed::Box, vec::Vec};
+
+        let heap: Heap<InlineAligned<4096, 8>> = Heap::new();
+        let mut v = Vec::new_in(&heap);
+        v.extend_from_slice(&[1u32, 2, 3]);
+        v.reserve_exact(5);
+        assert_eq!(v, [1, 2, 3]);
+        assert_eq!(heap.remained(), 4096 - 8 * 4);
+        drop(v);
+        assert_eq!(heap.remained(), 4096);
+
+        let b = Box::new_in(7u64, &heap);
+        assert_eq!(*b, 7);
+        assert_eq!(heap.remained(), 4096 - 8);
+    }
+
+    #[cfg(feature = "allocator-api2")]
+    #[test]
+    fn test_shrink_keeps_padding() {
+        use ::allocator_api2::alloc::Allocator;
+
+        let heap: Heap<InlineAligned<64, 8>> = Heap::new();
+        let l0 = Layout::new::<u8>();
+        let l1 = Layout::from_size_align(16, 8).unwrap();
+        let l2 = Layout::from_size_align(16, 1).unwrap();
+        let a = heap.allocate(l0).unwrap().cast::<u8>();
+        let p = heap.allocate(l1).unwrap().cast::<u8>();
+        let q = heap.allocate(l0).unwrap().cast::<u8>();
+        // Not the most recent allocation, so it stays where it is
+        let s = unsafe { heap.shrink(p, l1, l2) }.unwrap();
+        assert_eq!(s.cast::<u8>(), p);
+
+        // Its padding is still given back once it's on top
+        unsafe {
+            heap.deallocate(q, l0);
+            heap.deallocate(p, l2);
+            heap.deallocate(a, l0);
+        }
+        assert_eq!(heap.remained(), 64);
+    }
+
+    #[cfg(all(feature = "stats", feature = "allocator-api2"))]
+    #[test]
+    fn test_allocator_realloc_lost() {
+        use ::allocator_api2::vec::Vec;
+
+        let heap: Heap<InlineAligned<128, 8>> = Heap::new();
+        let mut v = Vec::new_in(&heap);
+        v.extend_from_slice(&[1u32, 2, 3]);
+        let b = ::allocator_api2::boxed::Box::new_in(7u64, &heap);
+        // Not on top, so the old block is lost to the copy rather than leaked
+        v.reserve_exact(5);
+        assert_eq!(v, [1, 2, 3]);
+        assert_eq!(heap.realloc_lost_bytes(), 16);
+        assert_eq!(heap.leaked_bytes(), 0);
+        drop(b);
+    }
+
+    #[cfg(feature = "allocator-api2")]
+    #[test]
+    fn test_zero_sized_allocator_api2() {
+        use ::allocator_api2::boxed::Box;
+
+        let heap: Heap<Inline<64>> = Heap::new();
+        let budget = heap.budget(0);
+        let b = Box::new_in([0u64; 0], &budget);
+        assert_eq!(b.as_ptr() as usize % 8, 0);
+        drop(b);
+        assert_eq!(heap.remained(), 64);
+
+        // Not even tried on the exhausted primary heap
+        let chain = Chain::new(Heap::<Inline<0>>::new(), Heap::<Inline<64>>::new());
+        chain
+            .primary()
+            .set_oom_hook(|_| panic!("zero-sized allocation reached the heap"));
+        let b = Box::new_in((), &chain);
+        drop(b);
+        assert_eq!(chain.fallback().remained(), 64);
+    }
 }
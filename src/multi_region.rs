@@ -0,0 +1,211 @@
+use super::*;
+
+/// A bump heap spanning up to `N` non-contiguous memory regions.
+///
+/// It's similar to [heap5 in FreeRTOS](https://github.com/FreeRTOS/FreeRTOS-Kernel/blob/main/portable/MemMang/heap_5.c).
+/// Each allocation is served by the first region that can fit it, so the
+/// leftovers of earlier regions are still used by smaller allocations.
+///
+/// Failures are reported to [`MultiRegion::set_oom_hook`], not to the hooks
+/// of the regions.
+///
+/// ```rust
+/// use core::mem::MaybeUninit;
+/// use heap1::MultiRegion;
+///
+/// static HEAP: MultiRegion<2> = MultiRegion::new();
+///
+/// fn main() {
+///     static mut SRAM1: [MaybeUninit<u8>; 1024] = [MaybeUninit::uninit(); 1024];
+///     static mut SRAM2: [MaybeUninit<u8>; 4096] = [MaybeUninit::uninit(); 4096];
+///     HEAP.init([unsafe { &mut *&raw mut SRAM1 }, unsafe { &mut *&raw mut SRAM2 }])
+///         .unwrap();
+///     assert_eq!(HEAP.remained(), 5120);
+/// }
+/// ```
+pub struct MultiRegion<const N: usize> {
+    regions: [Heap<Pointer>; N],
+    oom: Oom,
+}
+
+#[allow(clippy::new_without_default)]
+impl<const N: usize> MultiRegion<N> {
+    /// Create a heap without any memory, see [`MultiRegion::init`].
+    pub const fn new() -> Self {
+        Self {
+            regions: [const { Heap::empty() }; N],
+            oom: Oom::new(),
+        }
+    }
+
+    /// Initialize all regions at once, in the order they are tried.
+    ///
+    /// Nothing is initialized if any of the regions already is.
+    pub fn init(
+        &self,
+        mems: [&'static mut [MaybeUninit<u8>]; N],
+    ) -> Result<(), AlreadyInitialized> {
+        if self.regions.iter().any(|r| r.storage.is_set()) {
+            return Err(AlreadyInitialized);
+        }
+        for (region, mem) in self.regions.iter().zip(mems) {
+            region.init(mem)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the regions, each of them can be initialized or extended on its own.
+    pub fn regions(&self) -> &[Heap<Pointer>; N] {
+        &self.regions
+    }
+
+    /// Returns the amount of unused bytes in all regions.
+    pub fn remained(&self) -> usize {
+        self.regions.iter().map(Heap::remained).sum()
+    }
+
+    /// Returns the total amount of bytes in all regions.
+    pub fn capacity(&self) -> usize {
+        self.regions.iter().map(Heap::capacity).sum()
+    }
+
+    /// Set a function to be called whenever an allocation fails in all regions.
+    pub fn set_oom_hook(&self, hook: fn(&HeapOomInfo)) {
+        self.oom.set_hook(hook);
+    }
+
+    /// Returns the most recent allocation failure.
+    pub fn last_oom(&self) -> Option<HeapOomInfo> {
+        self.oom.last(self.capacity())
+    }
+
+    /// Returns `true` if `ptr` points into any of the regions.
+    pub fn owns(&self, ptr: *const u8) -> bool {
+        self.regions.iter().any(|r| r.owns(ptr))
+    }
+
+    #[cold]
+    fn out_of_memory(&self, layout: Layout) -> *mut u8 {
+        self.oom.report(HeapOomInfo {
+            layout,
+            remained: self.remained(),
+            capacity: self.capacity(),
+        });
+        ptr::null_mut()
+    }
+}
+
+unsafe impl<const N: usize> GlobalAlloc for MultiRegion<N> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(p) = self
+            .regions
+            .iter()
+            .find_map(|r| r.bump_extended(layout, None).ok())
+        {
+            return p.as_ptr();
+        }
+        self.out_of_memory(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if let Some(r) = self.regions.iter().find(|r| r.owns(ptr)) {
+            unsafe { GlobalAlloc::dealloc(r, ptr, layout) }
+        }
+    }
+}
+
+// ------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(not(feature = "std"))]
+    use alloc::boxed::Box;
+
+    #[test]
+    fn test_multi_region() {
+        let heap: MultiRegion<3> = MultiRegion::new();
+        assert_eq!(heap.remained(), 0);
+        let mut mem0 = [0u64; 2];
+        let mut mem1 = [0u64; 8];
+        let bytes = |m: &mut [u64]| {
+            NonNull::slice_from_raw_parts(NonNull::from(&mut *m).cast::<u8>(), size_of_val(m))
+        };
+        unsafe { heap.regions()[0].init_with_nonnull(bytes(&mut mem0)) }.unwrap();
+        unsafe { heap.regions()[1].init_with_nonnull(bytes(&mut mem1)) }.unwrap();
+        assert_eq!(heap.remained(), 80);
+        assert_eq!(heap.capacity(), 80);
+
+        let l = Layout::new::<[u64; 2]>();
+        let p0 = unsafe { heap.alloc(l) };
+        let p1 = unsafe { heap.alloc(l) };
+        assert!(heap.regions()[0].owns(p0));
+        assert!(heap.regions()[1].owns(p1));
+        // The first region is full, the second one still fits smaller allocations
+        let p2 = unsafe { heap.alloc(Layout::new::<u32>()) };
+        assert!(heap.regions()[1].owns(p2));
+        assert_eq!(heap.remained(), 44);
+        assert!(unsafe { heap.alloc(Layout::new::<[u64; 6]>()) }.is_null());
+
+        unsafe { heap.dealloc(p2, Layout::new::<u32>()) };
+        unsafe { heap.dealloc(p0, l) };
+        assert_eq!(heap.remained(), 64);
+    }
+
+    #[test]
+    fn test_multi_region_extended() {
+        let heap: MultiRegion<2> = MultiRegion::new();
+        let mut mem = [0u64; 64];
+        let region = NonNull::from(&mut mem).cast::<u8>();
+        unsafe { heap.regions()[0].extend(region, 16) }.unwrap();
+        unsafe { heap.regions()[0].extend(region.byte_add(16), 496) }.unwrap();
+        let remained = heap.remained();
+
+        // Only fits the extension of the first region
+        let l = Layout::new::<[u64; 8]>();
+        let p = unsafe { heap.alloc(l) };
+        assert!(heap.regions()[0].owns(p));
+        assert_eq!(heap.remained(), remained - 64);
+        unsafe { heap.dealloc(p, l) };
+        assert_eq!(heap.remained(), remained);
+    }
+
+    #[test]
+    fn test_multi_region_oom() {
+        static HEAP: MultiRegion<2> = MultiRegion::new();
+        static FAILED: AtomicUsize = AtomicUsize::new(0);
+        HEAP.set_oom_hook(|info| {
+            assert_eq!(info.remained, 48);
+            FAILED.fetch_add(1, Ordering::Relaxed);
+        });
+        HEAP.init([
+            Box::leak(Box::new([MaybeUninit::uninit(); 16])),
+            Box::leak(Box::new([MaybeUninit::uninit(); 32])),
+        ])
+        .unwrap();
+
+        let l = Layout::new::<[u8; 40]>();
+        assert!(unsafe { HEAP.alloc(l) }.is_null());
+        assert_eq!(FAILED.load(Ordering::Relaxed), 1);
+        let oom = HEAP.last_oom().unwrap();
+        assert_eq!((oom.layout, oom.capacity), (l, 48));
+    }
+
+    #[test]
+    fn test_multi_region_init() {
+        let heap: MultiRegion<2> = MultiRegion::new();
+        heap.regions()[1]
+            .init(Box::leak(Box::new([MaybeUninit::uninit(); 32])))
+            .unwrap();
+        // Fails before touching the first region
+        assert!(
+            heap.init([
+                Box::leak(Box::new([MaybeUninit::uninit(); 16])),
+                Box::leak(Box::new([MaybeUninit::uninit(); 16])),
+            ])
+            .is_err()
+        );
+        assert_eq!(heap.regions()[0].capacity(), 0);
+        assert_eq!(heap.capacity(), 32);
+    }
+}
@@ -0,0 +1,125 @@
+use portable_atomic::{AtomicUsize, Ordering};
+
+/// The number of padded allocations whose padding is remembered.
+const RECORDS: usize = 8;
+
+const PAD_BITS: u32 = usize::BITS / 4;
+const NONE: usize = 0;
+
+/// Remembers the alignment padding of recent allocations, so giving a block back
+/// also gives back its padding.
+///
+/// A record is keyed by `remained` right after the allocation, which is unique among
+/// live blocks. In `remained` terms the padding always follows the block, so a block
+/// at `after` of `len` bytes was placed at `after + len + pad`.
+///
+/// Only the most recent padded allocations are remembered, the padding of older ones
+/// is leaked when they are given back. So is padding that doesn't fit `PAD_BITS`,
+/// or whose key doesn't fit the remaining bits, on 32-bit targets that's padding of
+/// 256 bytes or more and keys of 16 MiB or more.
+pub(crate) struct Padding {
+    records: [AtomicUsize; RECORDS],
+}
+
+/// A record found by [`Padding::find`], dropped with [`Padding::take`].
+pub(crate) struct Found {
+    slot: usize,
+    record: usize,
+    /// `remained` before the block was placed.
+    pub(crate) prev: usize,
+}
+
+/// Returns `NONE` if the record doesn't fit, a record always has some padding.
+///
+/// The key is in the high bits, so records compare like their keys.
+fn pack(key: usize, pad: usize) -> usize {
+    if pad == 0 || pad >> PAD_BITS != 0 || key >> (usize::BITS - PAD_BITS) != 0 {
+        return NONE;
+    }
+    key << PAD_BITS | pad
+}
+
+fn unpack(record: usize) -> (usize, usize) {
+    (record >> PAD_BITS, record & ((1 << PAD_BITS) - 1))
+}
+
+impl Padding {
+    pub(crate) const fn new() -> Self {
+        Self {
+            records: [const { AtomicUsize::new(NONE) }; RECORDS],
+        }
+    }
+
+    /// Forget all records, when `remained` is moved by anything but a single block.
+    pub(crate) fn clear(&self) {
+        for r in &self.records {
+            r.store(NONE, Ordering::Release);
+        }
+    }
+
+    /// Record a block that left `remained` at `after` with `pad` bytes of padding.
+    ///
+    /// A record left behind with the same key is stale, as keys are unique among live
+    /// blocks, so it's replaced even if this block has no padding to record.
+    ///
+    /// Must happen before the block is handed out.
+    pub(crate) fn push(&self, after: usize, pad: usize) {
+        let record = pack(after, pad);
+        for r in &self.records {
+            let current = r.load(Ordering::Acquire);
+            if current != NONE
+                && unpack(current).0 == after
+                && r.compare_exchange(current, record, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return;
+            }
+        }
+        if record == NONE {
+            return;
+        }
+        // Take a free slot, or else replace the oldest record, which has the highest key.
+        let mut oldest = (0, NONE);
+        for (i, r) in self.records.iter().enumerate() {
+            let current = r.load(Ordering::Acquire);
+            if current == NONE {
+                if r.compare_exchange(NONE, record, Ordering::AcqRel, Ordering::Relaxed)
+                    .is_ok()
+                {
+                    return;
+                }
+            } else if current > oldest.1 {
+                oldest = (i, current);
+            }
+        }
+        if oldest.1 > record {
+            // Raced with another allocation if this fails, either record may go.
+            let _ = self.records[oldest.0].compare_exchange(
+                oldest.1,
+                record,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    /// Look up the record of the block at `after` of `len` bytes.
+    pub(crate) fn find(&self, after: usize, len: usize) -> Option<Found> {
+        self.records.iter().enumerate().find_map(|(slot, r)| {
+            let record = r.load(Ordering::Acquire);
+            let (key, pad) = unpack(record);
+            (record != NONE && key == after).then_some(Found {
+                slot,
+                record,
+                prev: after + len + pad,
+            })
+        })
+    }
+
+    /// Drop a record, returns `false` if it's gone already.
+    pub(crate) fn take(&self, found: &Found) -> bool {
+        self.records[found.slot]
+            .compare_exchange(found.record, NONE, Ordering::AcqRel, Ordering::Relaxed)
+            .is_ok()
+    }
+}
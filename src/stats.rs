@@ -0,0 +1,108 @@
+use core::fmt;
+use portable_atomic::{AtomicUsize, Ordering};
+
+/// Usage statistics kept alongside the heap.
+pub(crate) struct Stats {
+    pub(crate) min_remained: AtomicUsize,
+    pub(crate) alloc_count: AtomicUsize,
+    pub(crate) failed_count: AtomicUsize,
+    pub(crate) padding: AtomicUsize,
+    pub(crate) realloc_lost: AtomicUsize,
+    pub(crate) leak_count: AtomicUsize,
+    pub(crate) leaked: AtomicUsize,
+}
+
+impl Stats {
+    pub(crate) const fn new(size: usize) -> Self {
+        Self {
+            min_remained: AtomicUsize::new(size),
+            alloc_count: AtomicUsize::new(0),
+            failed_count: AtomicUsize::new(0),
+            padding: AtomicUsize::new(0),
+            realloc_lost: AtomicUsize::new(0),
+            leak_count: AtomicUsize::new(0),
+            leaked: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn reset(&self, size: usize) {
+        self.min_remained.store(size, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_alloc(&self, remained: usize, padding: usize) {
+        self.min_remained.fetch_min(remained, Ordering::Relaxed);
+        self.alloc_count.fetch_add(1, Ordering::Relaxed);
+        self.padding.fetch_add(padding, Ordering::Relaxed);
+    }
+
+    /// Padding given back along with its block isn't lost anymore.
+    pub(crate) fn on_release_padding(&self, padding: usize) {
+        self.padding.fetch_sub(padding, Ordering::Relaxed);
+    }
+
+    /// The most recent block was placed again, trading `old` padding for `new`.
+    pub(crate) fn on_resize(&self, remained: usize, old: usize, new: usize) {
+        self.min_remained.fetch_min(remained, Ordering::Relaxed);
+        self.padding.fetch_sub(old, Ordering::Relaxed);
+        self.padding.fetch_add(new, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_failure(&self) {
+        self.failed_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_realloc_lost(&self, size: usize) {
+        self.realloc_lost.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_leak(&self, size: usize) {
+        self.leak_count.fetch_add(1, Ordering::Relaxed);
+        self.leaked.fetch_add(size, Ordering::Relaxed);
+    }
+}
+
+/// A snapshot of the heap usage, see [`Heap::stats`](crate::Heap::stats).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct HeapStats {
+    /// Total amount of bytes.
+    pub capacity: usize,
+    /// Allocated bytes, including padding.
+    pub used: usize,
+    /// Unused bytes.
+    pub remaining: usize,
+    /// The most bytes ever used at once.
+    pub peak: usize,
+    /// Number of successful allocations.
+    pub alloc_count: usize,
+    /// Number of failed allocations.
+    pub failed_count: usize,
+    /// Bytes lost to alignment padding, padding given back with its block no longer counts.
+    pub padding: usize,
+    /// Bytes left behind when `realloc` had to copy.
+    pub realloc_lost: usize,
+    /// Number of deallocations that couldn't give the memory back.
+    pub leak_count: usize,
+    /// Bytes deallocated but not given back.
+    pub leaked: usize,
+}
+
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "capacity: {}, used: {}, remaining: {}, peak: {}, allocs: {}, failed: {}, padding: {}, realloc lost: {}, leaks: {}, leaked: {}",
+            self.capacity,
+            self.used,
+            self.remaining,
+            self.peak,
+            self.alloc_count,
+            self.failed_count,
+            self.padding,
+            self.realloc_lost,
+            self.leak_count,
+            self.leaked
+        )
+    }
+}